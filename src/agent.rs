@@ -0,0 +1,169 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Sender};
+
+use crate::audio_manager::{self, CoreAudioBackend};
+use crate::config::{ConfigStore, MqttConfig};
+use crate::control::{self, ControlServer};
+use crate::controller::{run_enforcement_worker, AudioEvent, Controller, WorkerDeps};
+use crate::device_watcher::DeviceWatcher;
+use crate::health_monitor::HealthMonitor;
+use crate::history::{self, History};
+use crate::hooks;
+use crate::logging;
+use crate::metrics;
+#[cfg(feature = "mqtt")]
+use crate::mqtt;
+use crate::session_events::SessionEvents;
+use crate::spans;
+use crate::trace::TraceRecorder;
+use crate::tray_ui;
+
+pub fn run() {
+    let config = Arc::new(ConfigStore::load());
+    logging::init(&config);
+    seed_device_catalog(&config);
+    let cfg = config.get();
+    tracing::info!(
+        version = env!("CARGO_PKG_VERSION"),
+        lock_enabled = cfg.lock_enabled,
+        locked_uid = cfg.locked_uid.as_deref(),
+        "starting"
+    );
+
+    let recorder = cfg
+        .trace_path
+        .as_ref()
+        .and_then(|path| match TraceRecorder::create(path, &cfg) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                tracing::warn!(path = %path.display(), error = %e, "could not start the trace");
+                None
+            }
+        })
+        .map(Arc::new);
+    let controller = Arc::new(
+        Controller::from_config(Arc::new(CoreAudioBackend), &cfg).with_recorder(recorder.clone()),
+    );
+
+    let history = Arc::new(History::open(history::default_path()));
+    hooks::start(controller.subscribe(), config.clone());
+
+    let (tx, rx) = unbounded();
+    let monitor = cfg.silence_monitor.then(|| {
+        Arc::new(HealthMonitor::new(
+            tx.clone(),
+            cfg.silence_threshold_dbfs,
+            Duration::from_secs(cfg.silence_secs),
+        ))
+    });
+    let watcher = Arc::new(DeviceWatcher::start(tx.clone()).expect("audio watcher"));
+    let session_events = SessionEvents::start(tx.clone());
+
+    let control = match ControlServer::start(
+        &control::socket_path(),
+        controller.clone(),
+        config.clone(),
+        tx.clone(),
+    ) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            tracing::warn!(error = %e, "control socket unavailable");
+            None
+        }
+    };
+
+    if let Some(settings) = cfg.mqtt.clone() {
+        start_mqtt(settings, controller.clone(), config.clone(), tx.clone());
+    }
+
+    if let Some(addr) = cfg.metrics_addr.as_deref() {
+        match metrics::serve(addr, controller.clone()) {
+            Ok(()) => tracing::info!(addr, "serving metrics"),
+            Err(e) => tracing::warn!(addr, error = %e, "metrics endpoint unavailable"),
+        }
+    }
+
+    let (app, ui) = tray_ui::init_app(controller.clone(), config.clone(), history.clone(), tx);
+
+    run_enforcement_worker(
+        rx,
+        WorkerDeps {
+            controller: controller.clone(),
+            ui: ui.clone(),
+            watcher: watcher.clone(),
+            monitor: monitor.clone(),
+            recorder,
+            control,
+            history,
+            watchdog: cfg
+                .watchdog_secs
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+        },
+    );
+
+    let initial_controller = controller.clone();
+    let initial_ui = ui.clone();
+    let initial_watcher = watcher.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        if let Err(e) = initial_controller.enforce_now() {
+            tracing::warn!(error = ?e, "enforcing the lock failed");
+        }
+        let locked = initial_controller.locked_input();
+        initial_watcher.watch_locked_device(locked.clone());
+        if let Some(monitor) = monitor {
+            monitor.follow(locked);
+        }
+        initial_ui.request_refresh();
+    });
+
+    app.run();
+    spans::flush();
+
+    drop(session_events);
+    drop(watcher);
+}
+
+#[cfg(feature = "mqtt")]
+fn start_mqtt(
+    settings: MqttConfig,
+    controller: Arc<Controller>,
+    config: Arc<ConfigStore>,
+    events: Sender<AudioEvent>,
+) {
+    mqtt::start(settings, controller, config, events);
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn start_mqtt(
+    settings: MqttConfig,
+    _controller: Arc<Controller>,
+    _config: Arc<ConfigStore>,
+    _events: Sender<AudioEvent>,
+) {
+    tracing::warn!(host = %settings.host, "mqtt is set, but this build has no MQTT client (feature mqtt)");
+}
+
+/// On first launch every device that is already connected counts as known,
+/// so only devices that show up later go through approval.
+fn seed_device_catalog(config: &ConfigStore) {
+    if !config.get().known_uids.is_empty() {
+        return;
+    }
+
+    let Ok(devices) = audio_manager::list_input_devices() else {
+        return;
+    };
+
+    config.update(|c| {
+        c.known_uids = devices.into_iter().map(|d| d.uid).collect();
+        if let Some(uid) = c.locked_uid.clone() {
+            if !c.known_uids.contains(&uid) {
+                c.known_uids.push(uid);
+            }
+        }
+    });
+}
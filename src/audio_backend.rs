@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+
+pub type AudioDeviceID = u32;
+
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: AudioDeviceID,
+    pub uid: String,
+    pub name: String,
+    pub input_channels: u32,
+}
+
+/// A client process of the audio server (macOS 14+).
+#[derive(Debug, Clone)]
+pub struct AudioProcess {
+    pub pid: i32,
+    pub bundle_id: Option<String>,
+    pub is_running_input: bool,
+    /// Input devices the process is currently using.
+    pub devices: Vec<AudioDeviceID>,
+}
+
+impl AudioProcess {
+    /// Soundstoic itself, capturing for the silence monitor.
+    pub fn is_self(&self) -> bool {
+        self.pid == std::process::id() as i32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioError {
+    OsStatus(i32),
+    NotFound,
+}
+
+/// A Core Audio four-character code as text, e.g. `usb ` or `!obj`, with
+/// bytes that are not printable shown as `?`.
+pub fn four_cc(code: u32) -> String {
+    code.to_be_bytes()
+        .iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '?'
+            }
+        })
+        .collect()
+}
+
+/// An OSStatus as its four-character code where it is one (most HAL errors
+/// are, e.g. `'!dev'`), otherwise as the number.
+pub fn os_status_text(status: i32) -> String {
+    let bytes = status.to_be_bytes();
+    if bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        format!("'{}' ({})", four_cc(status as u32), status)
+    } else {
+        status.to_string()
+    }
+}
+
+/// The "In use by" lines of the menu: each other process capturing audio,
+/// by bundle ID or PID, with the names of the inputs it uses.
+pub fn in_use_by(processes: &[AudioProcess], devices: &[DeviceInfo]) -> Vec<String> {
+    processes
+        .iter()
+        .filter(|p| p.is_running_input && !p.is_self())
+        .map(|process| {
+            let who = process
+                .bundle_id
+                .clone()
+                .unwrap_or_else(|| format!("PID {}", process.pid));
+            let names: Vec<&str> = process
+                .devices
+                .iter()
+                .filter_map(|id| devices.iter().find(|d| d.id == *id))
+                .map(|d| d.name.as_str())
+                .collect();
+            if names.is_empty() {
+                who
+            } else {
+                format!("{} — {}", who, names.join(", "))
+            }
+        })
+        .collect()
+}
+
+/// The device and process model the agent works against. `CoreAudioBackend`
+/// talks to the HAL; other implementations stand in for it off macOS.
+pub trait AudioBackend: Send + Sync {
+    fn list_input_devices(&self) -> Result<Vec<DeviceInfo>, AudioError>;
+    fn get_default_input_device(&self) -> Result<AudioDeviceID, AudioError>;
+    fn set_default_input_device(&self, device_id: AudioDeviceID) -> Result<(), AudioError>;
+    fn device_name_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError>;
+    fn device_uid_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError>;
+    fn device_id_for_uid(&self, uid: &str) -> Result<AudioDeviceID, AudioError>;
+    fn device_is_alive(&self, device_id: AudioDeviceID) -> Result<bool, AudioError>;
+    fn device_is_running_somewhere(&self, device_id: AudioDeviceID) -> Result<bool, AudioError>;
+    fn list_processes(&self) -> Result<Vec<AudioProcess>, AudioError>;
+}
+
+/// The `AudioBackend` methods, for wrappers and simulations that act per call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    ListInputDevices,
+    GetDefaultInputDevice,
+    SetDefaultInputDevice,
+    DeviceNameById,
+    DeviceUidById,
+    DeviceIdForUid,
+    DeviceIsAlive,
+    DeviceIsRunningSomewhere,
+    ListProcesses,
+}
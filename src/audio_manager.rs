@@ -2,24 +2,13 @@ use std::{ffi::c_void, mem, ptr};
 
 use core_foundation::base::TCFType;
 use core_foundation::string::{CFString, CFStringRef};
+use tracing::instrument;
 
 use crate::audio_sys::*;
 
-#[derive(Debug, Clone)]
-pub struct DeviceInfo {
-    pub id: AudioDeviceID,
-    pub uid: String,
-    pub name: String,
-    pub input_channels: u32,
-}
-
-#[derive(Debug)]
-pub enum AudioError {
-    OsStatus(OSStatus),
-    NotFound,
-}
+pub use crate::audio_backend::{AudioBackend, AudioError, AudioProcess, DeviceInfo};
 
-fn ok(status: OSStatus) -> Result<(), AudioError> {
+pub fn ok(status: OSStatus) -> Result<(), AudioError> {
     if status == 0 {
         Ok(())
     } else {
@@ -27,7 +16,7 @@ fn ok(status: OSStatus) -> Result<(), AudioError> {
     }
 }
 
-fn get_cfstring_property(
+pub fn get_cfstring_property(
     object_id: AudioObjectID,
     selector: AudioObjectPropertySelector,
     scope: AudioObjectPropertyScope,
@@ -92,28 +81,59 @@ fn get_cfstring_property(
     }
 }
 
-fn get_device_ids() -> Result<Vec<AudioDeviceID>, AudioError> {
+pub fn get_u32_property(
+    object_id: AudioObjectID,
+    selector: AudioObjectPropertySelector,
+    scope: AudioObjectPropertyScope,
+) -> Result<u32, AudioError> {
     unsafe {
         let address = AudioObjectPropertyAddress {
-            mSelector: K_AUDIO_HARDWARE_PROPERTY_DEVICES,
-            mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+            mSelector: selector,
+            mScope: scope,
+            mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+        };
+
+        let mut value: u32 = 0;
+        let mut size = mem::size_of::<u32>() as u32;
+        ok(AudioObjectGetPropertyData(
+            object_id,
+            &address,
+            0,
+            ptr::null(),
+            &mut size,
+            (&mut value as *mut u32).cast::<c_void>(),
+        ))?;
+
+        Ok(value)
+    }
+}
+
+fn get_object_list_property(
+    object_id: AudioObjectID,
+    selector: AudioObjectPropertySelector,
+    scope: AudioObjectPropertyScope,
+) -> Result<Vec<AudioObjectID>, AudioError> {
+    unsafe {
+        let address = AudioObjectPropertyAddress {
+            mSelector: selector,
+            mScope: scope,
             mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
         };
 
         let mut size: u32 = 0;
         ok(AudioObjectGetPropertyDataSize(
-            K_AUDIO_OBJECT_SYSTEM_OBJECT,
+            object_id,
             &address,
             0,
             ptr::null(),
             &mut size,
         ))?;
 
-        let count = (size as usize) / mem::size_of::<AudioDeviceID>();
+        let count = (size as usize) / mem::size_of::<AudioObjectID>();
         let mut ids = vec![0u32; count];
 
         ok(AudioObjectGetPropertyData(
-            K_AUDIO_OBJECT_SYSTEM_OBJECT,
+            object_id,
             &address,
             0,
             ptr::null(),
@@ -121,10 +141,46 @@ fn get_device_ids() -> Result<Vec<AudioDeviceID>, AudioError> {
             ids.as_mut_ptr().cast::<c_void>(),
         ))?;
 
+        ids.truncate((size as usize) / mem::size_of::<AudioObjectID>());
         Ok(ids)
     }
 }
 
+pub fn get_f64_property(
+    object_id: AudioObjectID,
+    selector: AudioObjectPropertySelector,
+    scope: AudioObjectPropertyScope,
+) -> Result<f64, AudioError> {
+    unsafe {
+        let address = AudioObjectPropertyAddress {
+            mSelector: selector,
+            mScope: scope,
+            mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+        };
+
+        let mut value: f64 = 0.0;
+        let mut size = mem::size_of::<f64>() as u32;
+        ok(AudioObjectGetPropertyData(
+            object_id,
+            &address,
+            0,
+            ptr::null(),
+            &mut size,
+            (&mut value as *mut f64).cast::<c_void>(),
+        ))?;
+
+        Ok(value)
+    }
+}
+
+pub fn get_device_ids() -> Result<Vec<AudioDeviceID>, AudioError> {
+    get_object_list_property(
+        K_AUDIO_OBJECT_SYSTEM_OBJECT,
+        K_AUDIO_HARDWARE_PROPERTY_DEVICES,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+    )
+}
+
 fn get_input_channel_count(device_id: AudioDeviceID) -> Result<u32, AudioError> {
     unsafe {
         let address = AudioObjectPropertyAddress {
@@ -159,7 +215,8 @@ fn get_input_channel_count(device_id: AudioDeviceID) -> Result<u32, AudioError>
 
         let abl = buffer.as_ptr() as *const AudioBufferList;
         let abl = &*abl;
-        let buffers = std::slice::from_raw_parts(abl.mBuffers.as_ptr(), abl.mNumberBuffers as usize);
+        let buffers =
+            std::slice::from_raw_parts(abl.mBuffers.as_ptr(), abl.mNumberBuffers as usize);
 
         let mut channels = 0u32;
         for b in buffers {
@@ -179,13 +236,7 @@ fn has_input_streams(device_id: AudioDeviceID) -> bool {
         };
 
         let mut size: u32 = 0;
-        let status = AudioObjectGetPropertyDataSize(
-            device_id,
-            &address,
-            0,
-            ptr::null(),
-            &mut size,
-        );
+        let status = AudioObjectGetPropertyDataSize(device_id, &address, 0, ptr::null(), &mut size);
         if status != 0 {
             return false;
         }
@@ -207,7 +258,9 @@ pub fn list_input_devices() -> Result<Vec<DeviceInfo>, AudioError> {
                     is_input = true;
                 }
             }
-            Err(_) => {}
+            Err(e) => {
+                tracing::debug!(device_id = id, error = ?e, "could not read the input channel count");
+            }
         }
 
         if !is_input && has_input_streams(id) {
@@ -218,10 +271,18 @@ pub fn list_input_devices() -> Result<Vec<DeviceInfo>, AudioError> {
             continue;
         }
 
-        let name = get_cfstring_property(id, K_AUDIO_OBJECT_PROPERTY_NAME, K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL)
-            .unwrap_or_else(|_| "<unknown>".to_string());
-        let uid = get_cfstring_property(id, K_AUDIO_DEVICE_PROPERTY_DEVICE_UID, K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL)
-            .unwrap_or_else(|_| "<no-uid>".to_string());
+        let name = get_cfstring_property(
+            id,
+            K_AUDIO_OBJECT_PROPERTY_NAME,
+            K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        )
+        .unwrap_or_else(|_| "<unknown>".to_string());
+        let uid = get_cfstring_property(
+            id,
+            K_AUDIO_DEVICE_PROPERTY_DEVICE_UID,
+            K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        )
+        .unwrap_or_else(|_| "<no-uid>".to_string());
 
         out.push(DeviceInfo {
             id,
@@ -294,6 +355,32 @@ pub fn device_uid_by_id(device_id: AudioDeviceID) -> Result<String, AudioError>
     )
 }
 
+pub fn device_is_alive(device_id: AudioDeviceID) -> Result<bool, AudioError> {
+    let alive = get_u32_property(
+        device_id,
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_ALIVE,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+    )?;
+    Ok(alive != 0)
+}
+
+pub fn device_is_running_somewhere(device_id: AudioDeviceID) -> Result<bool, AudioError> {
+    let running = get_u32_property(
+        device_id,
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_RUNNING_SOMEWHERE,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+    )?;
+    Ok(running != 0)
+}
+
+pub fn device_sample_rate(device_id: AudioDeviceID) -> Result<f64, AudioError> {
+    get_f64_property(
+        device_id,
+        K_AUDIO_DEVICE_PROPERTY_NOMINAL_SAMPLE_RATE,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+    )
+}
+
 pub fn device_id_for_uid(uid: &str) -> Result<AudioDeviceID, AudioError> {
     // Prefer the HAL translation API when available, fall back to enumeration.
     if let Ok(id) = device_id_for_uid_via_translation(uid) {
@@ -360,3 +447,148 @@ pub fn device_name_for_uid(uid: &str) -> Result<String, AudioError> {
 
     Err(AudioError::NotFound)
 }
+
+/// Lists audio client processes via the process objects added in macOS 14.
+/// Older systems report an unknown-property `OSStatus`.
+pub fn list_processes() -> Result<Vec<AudioProcess>, AudioError> {
+    let ids = get_object_list_property(
+        K_AUDIO_OBJECT_SYSTEM_OBJECT,
+        K_AUDIO_HARDWARE_PROPERTY_PROCESS_OBJECT_LIST,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+    )?;
+
+    let mut out = Vec::with_capacity(ids.len());
+    for id in ids {
+        let Ok(pid) = get_u32_property(
+            id,
+            K_AUDIO_PROCESS_PROPERTY_PID,
+            K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        ) else {
+            continue;
+        };
+        let bundle_id = get_cfstring_property(
+            id,
+            K_AUDIO_PROCESS_PROPERTY_BUNDLE_ID,
+            K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        )
+        .ok()
+        .filter(|b| !b.is_empty());
+        let is_running_input = get_u32_property(
+            id,
+            K_AUDIO_PROCESS_PROPERTY_IS_RUNNING_INPUT,
+            K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        )
+        .map(|v| v != 0)
+        .unwrap_or(false);
+        let devices = get_object_list_property(
+            id,
+            K_AUDIO_PROCESS_PROPERTY_DEVICES,
+            K_AUDIO_DEVICE_PROPERTY_SCOPE_INPUT,
+        )
+        .unwrap_or_default();
+
+        out.push(AudioProcess {
+            pid: pid as i32,
+            bundle_id,
+            is_running_input,
+            devices,
+        });
+    }
+
+    Ok(out)
+}
+
+/// `AudioBackend` over the Core Audio HAL.
+pub struct CoreAudioBackend;
+
+impl AudioBackend for CoreAudioBackend {
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "list_input_devices")
+    )]
+    fn list_input_devices(&self) -> Result<Vec<DeviceInfo>, AudioError> {
+        list_input_devices()
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "get_default_input_device")
+    )]
+    fn get_default_input_device(&self) -> Result<AudioDeviceID, AudioError> {
+        get_default_input_device()
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "set_default_input_device")
+    )]
+    fn set_default_input_device(&self, device_id: AudioDeviceID) -> Result<(), AudioError> {
+        set_default_input_device(device_id)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "device_name_by_id")
+    )]
+    fn device_name_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError> {
+        device_name_by_id(device_id)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "device_uid_by_id")
+    )]
+    fn device_uid_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError> {
+        device_uid_by_id(device_id)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "device_id_for_uid")
+    )]
+    fn device_id_for_uid(&self, uid: &str) -> Result<AudioDeviceID, AudioError> {
+        device_id_for_uid(uid)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "device_is_alive")
+    )]
+    fn device_is_alive(&self, device_id: AudioDeviceID) -> Result<bool, AudioError> {
+        device_is_alive(device_id)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "device_is_running_somewhere")
+    )]
+    fn device_is_running_somewhere(&self, device_id: AudioDeviceID) -> Result<bool, AudioError> {
+        device_is_running_somewhere(device_id)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "hal",
+        skip(self),
+        fields(call = "list_processes")
+    )]
+    fn list_processes(&self) -> Result<Vec<AudioProcess>, AudioError> {
+        list_processes()
+    }
+}
@@ -21,12 +21,27 @@ pub struct AudioObjectPropertyAddress {
     pub mElement: AudioObjectPropertyElement,
 }
 
-pub type AudioObjectPropertyListenerProc = Option<unsafe extern "C" fn(
-    in_object_id: AudioObjectID,
-    in_num_addresses: u32,
-    in_addresses: *const AudioObjectPropertyAddress,
-    in_client_data: *mut c_void,
-) -> OSStatus>;
+pub type AudioObjectPropertyListenerProc = Option<
+    unsafe extern "C" fn(
+        in_object_id: AudioObjectID,
+        in_num_addresses: u32,
+        in_addresses: *const AudioObjectPropertyAddress,
+        in_client_data: *mut c_void,
+    ) -> OSStatus,
+>;
+
+pub type AudioDeviceIOProc = Option<
+    unsafe extern "C" fn(
+        in_device: AudioObjectID,
+        in_now: *const c_void,
+        in_input_data: *const AudioBufferList,
+        in_input_time: *const c_void,
+        out_output_data: *mut AudioBufferList,
+        in_output_time: *const c_void,
+        in_client_data: *mut c_void,
+    ) -> OSStatus,
+>;
+pub type AudioDeviceIOProcID = AudioDeviceIOProc;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -52,6 +67,27 @@ pub struct AudioBufferList {
     pub mBuffers: [AudioBuffer; 1],
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct AudioValueRange {
+    pub mMinimum: f64,
+    pub mMaximum: f64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AudioStreamBasicDescription {
+    pub mSampleRate: f64,
+    pub mFormatID: u32,
+    pub mFormatFlags: u32,
+    pub mBytesPerPacket: u32,
+    pub mFramesPerPacket: u32,
+    pub mBytesPerFrame: u32,
+    pub mChannelsPerFrame: u32,
+    pub mBitsPerChannel: u32,
+    pub mReserved: u32,
+}
+
 const fn fourcc(tag: &[u8; 4]) -> u32 {
     u32::from_be_bytes(*tag)
 }
@@ -60,21 +96,66 @@ pub const K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL: u32 = fourcc(b"glob");
 pub const K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN: u32 = 0;
 
 pub const K_AUDIO_DEVICE_PROPERTY_SCOPE_INPUT: u32 = fourcc(b"inpt");
+pub const K_AUDIO_DEVICE_PROPERTY_SCOPE_OUTPUT: u32 = fourcc(b"outp");
 
 pub const K_AUDIO_HARDWARE_PROPERTY_DEVICES: u32 = fourcc(b"dev#");
 pub const K_AUDIO_HARDWARE_PROPERTY_DEFAULT_INPUT_DEVICE: u32 = fourcc(b"dIn ");
 pub const K_AUDIO_HARDWARE_PROPERTY_DEFAULT_OUTPUT_DEVICE: u32 = fourcc(b"dOut");
 pub const K_AUDIO_HARDWARE_PROPERTY_DEVICE_FOR_UID: u32 = fourcc(b"duid");
 pub const K_AUDIO_HARDWARE_PROPERTY_SERVICE_RESTARTED: u32 = fourcc(b"srst");
+pub const K_AUDIO_HARDWARE_PROPERTY_PROCESS_OBJECT_LIST: u32 = fourcc(b"prs#");
+
+pub const K_AUDIO_PROCESS_PROPERTY_PID: u32 = fourcc(b"ppid");
+pub const K_AUDIO_PROCESS_PROPERTY_BUNDLE_ID: u32 = fourcc(b"pbid");
+pub const K_AUDIO_PROCESS_PROPERTY_DEVICES: u32 = fourcc(b"pdv#");
+pub const K_AUDIO_PROCESS_PROPERTY_IS_RUNNING_INPUT: u32 = fourcc(b"piri");
 
 pub const K_AUDIO_OBJECT_PROPERTY_NAME: u32 = fourcc(b"name");
 pub const K_AUDIO_DEVICE_PROPERTY_DEVICE_UID: u32 = fourcc(b"uid ");
 pub const K_AUDIO_DEVICE_PROPERTY_STREAM_CONFIGURATION: u32 = fourcc(b"scfg");
 pub const K_AUDIO_DEVICE_PROPERTY_STREAMS: u32 = fourcc(b"stm#");
 pub const K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_ALIVE: u32 = fourcc(b"aliv");
+pub const K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_RUNNING_SOMEWHERE: u32 = fourcc(b"gone");
+pub const K_AUDIO_DEVICE_PROPERTY_NOMINAL_SAMPLE_RATE: u32 = fourcc(b"nsrt");
+
+pub const K_AUDIO_OBJECT_PROPERTY_MODEL_NAME: u32 = fourcc(b"lmod");
+pub const K_AUDIO_OBJECT_PROPERTY_MANUFACTURER: u32 = fourcc(b"lmak");
+pub const K_AUDIO_DEVICE_PROPERTY_MODEL_UID: u32 = fourcc(b"muid");
+pub const K_AUDIO_DEVICE_PROPERTY_TRANSPORT_TYPE: u32 = fourcc(b"tran");
+pub const K_AUDIO_DEVICE_PROPERTY_CLOCK_DOMAIN: u32 = fourcc(b"clkd");
+pub const K_AUDIO_DEVICE_PROPERTY_CONFIGURATION_APPLICATION: u32 = fourcc(b"capp");
+pub const K_AUDIO_DEVICE_PROPERTY_IS_HIDDEN: u32 = fourcc(b"hidn");
+pub const K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_RUNNING: u32 = fourcc(b"goin");
+pub const K_AUDIO_DEVICE_PROPERTY_DEVICE_CAN_BE_DEFAULT_DEVICE: u32 = fourcc(b"dflt");
+pub const K_AUDIO_DEVICE_PROPERTY_DEVICE_CAN_BE_DEFAULT_SYSTEM_DEVICE: u32 = fourcc(b"sflt");
+pub const K_AUDIO_DEVICE_PROPERTY_AVAILABLE_NOMINAL_SAMPLE_RATES: u32 = fourcc(b"nsr#");
+pub const K_AUDIO_DEVICE_PROPERTY_BUFFER_FRAME_SIZE: u32 = fourcc(b"fsiz");
+pub const K_AUDIO_DEVICE_PROPERTY_LATENCY: u32 = fourcc(b"ltnc");
+pub const K_AUDIO_DEVICE_PROPERTY_SAFETY_OFFSET: u32 = fourcc(b"saft");
+pub const K_AUDIO_DEVICE_PROPERTY_DATA_SOURCE: u32 = fourcc(b"ssrc");
+pub const K_AUDIO_DEVICE_PROPERTY_DATA_SOURCES: u32 = fourcc(b"ssc#");
+pub const K_AUDIO_DEVICE_PROPERTY_DATA_SOURCE_NAME_FOR_ID_CFSTRING: u32 = fourcc(b"lscn");
+
+pub const K_AUDIO_STREAM_PROPERTY_IS_ACTIVE: u32 = fourcc(b"sact");
+pub const K_AUDIO_STREAM_PROPERTY_DIRECTION: u32 = fourcc(b"sdir");
+pub const K_AUDIO_STREAM_PROPERTY_TERMINAL_TYPE: u32 = fourcc(b"term");
+pub const K_AUDIO_STREAM_PROPERTY_STARTING_CHANNEL: u32 = fourcc(b"schn");
+pub const K_AUDIO_STREAM_PROPERTY_VIRTUAL_FORMAT: u32 = fourcc(b"sfmt");
+pub const K_AUDIO_STREAM_PROPERTY_PHYSICAL_FORMAT: u32 = fourcc(b"pft ");
 
 #[link(name = "CoreAudio", kind = "framework")]
 extern "C" {
+    pub fn AudioObjectHasProperty(
+        in_object_id: AudioObjectID,
+        in_address: *const AudioObjectPropertyAddress,
+    ) -> u8;
+
+    pub fn AudioObjectIsPropertySettable(
+        in_object_id: AudioObjectID,
+        in_address: *const AudioObjectPropertyAddress,
+        out_is_settable: *mut u8,
+    ) -> OSStatus;
+
     pub fn AudioObjectGetPropertyDataSize(
         in_object_id: AudioObjectID,
         in_address: *const AudioObjectPropertyAddress,
@@ -114,4 +195,20 @@ extern "C" {
         in_listener: AudioObjectPropertyListenerProc,
         in_client_data: *mut c_void,
     ) -> OSStatus;
+
+    pub fn AudioDeviceCreateIOProcID(
+        in_device: AudioObjectID,
+        in_proc: AudioDeviceIOProc,
+        in_client_data: *mut c_void,
+        out_io_proc_id: *mut AudioDeviceIOProcID,
+    ) -> OSStatus;
+
+    pub fn AudioDeviceDestroyIOProcID(
+        in_device: AudioObjectID,
+        in_io_proc_id: AudioDeviceIOProcID,
+    ) -> OSStatus;
+
+    pub fn AudioDeviceStart(in_device: AudioObjectID, in_proc_id: AudioDeviceIOProcID) -> OSStatus;
+
+    pub fn AudioDeviceStop(in_device: AudioObjectID, in_proc_id: AudioDeviceIOProcID) -> OSStatus;
 }
@@ -38,6 +38,7 @@ pub fn is_enabled() -> bool {
 pub fn set_enabled(enabled: bool) -> Result<(), AutostartError> {
     unsafe {
         let Some(service) = main_app_service() else {
+            tracing::warn!(enabled, "SMAppService is not available");
             return Err(AutostartError::Unavailable);
         };
 
@@ -49,8 +50,10 @@ pub fn set_enabled(enabled: bool) -> Result<(), AutostartError> {
         };
 
         if ok.as_bool() {
+            tracing::info!(enabled, "updated the login item");
             Ok(())
         } else {
+            tracing::warn!(enabled, "could not update the login item");
             Err(AutostartError::Failed)
         }
     }
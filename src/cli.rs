@@ -0,0 +1,246 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::audio_backend::{AudioBackend, AudioError, DeviceInfo};
+use crate::config::ConfigStore;
+use crate::control;
+
+/// Subcommands that query or change devices once and exit, without the menu
+/// bar.
+pub const COMMANDS: &[&str] = &["list", "current", "set", "lock", "unlock", "status"];
+
+pub const USAGE: &str =
+    "usage: soundstoic list [--json] | current | set <uid|name> | lock <uid> | unlock | status";
+
+pub enum CliError {
+    /// The arguments were wrong; exits with status 2.
+    Usage(String),
+    Failed(String),
+}
+
+/// A device as `list --json` and the control socket report it.
+#[derive(Serialize)]
+pub struct ListedDevice {
+    pub id: u32,
+    pub uid: String,
+    pub name: String,
+    pub input_channels: u32,
+    pub default: bool,
+    pub locked: bool,
+}
+
+pub fn listed_devices(
+    backend: &dyn AudioBackend,
+    locked_uid: Option<&str>,
+) -> Result<Vec<ListedDevice>, AudioError> {
+    let default_id = backend.get_default_input_device().ok();
+    Ok(backend
+        .list_input_devices()?
+        .into_iter()
+        .map(|d| ListedDevice {
+            default: Some(d.id) == default_id,
+            locked: locked_uid == Some(d.uid.as_str()),
+            id: d.id,
+            uid: d.uid,
+            name: d.name,
+            input_channels: d.input_channels,
+        })
+        .collect())
+}
+
+pub fn run(
+    args: &[String],
+    backend: &dyn AudioBackend,
+    config: &ConfigStore,
+) -> Result<(), CliError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => list(backend, config, false),
+        ["list", "--json"] => list(backend, config, true),
+        ["current"] => current(backend),
+        ["set", device] => set(backend, device),
+        ["lock", uid] => lock(backend, config, uid),
+        ["unlock"] => unlock(config),
+        ["status"] => status(backend, config),
+        _ => Err(CliError::Usage(USAGE.to_string())),
+    }
+}
+
+fn devices(backend: &dyn AudioBackend) -> Result<Vec<DeviceInfo>, CliError> {
+    backend
+        .list_input_devices()
+        .map_err(|e| CliError::Failed(format!("could not list input devices: {:?}", e)))
+}
+
+fn list(backend: &dyn AudioBackend, config: &ConfigStore, json: bool) -> Result<(), CliError> {
+    let locked_uid = config.get().locked_uid;
+    let listed = listed_devices(backend, locked_uid.as_deref())
+        .map_err(|e| CliError::Failed(format!("could not list input devices: {:?}", e)))?;
+
+    if json {
+        let out =
+            serde_json::to_string_pretty(&listed).map_err(|e| CliError::Failed(e.to_string()))?;
+        println!("{}", out);
+        return Ok(());
+    }
+
+    // `*` marks the default input, `L` the locked one.
+    let width = listed.iter().map(|d| d.uid.len()).max().unwrap_or(0);
+    for device in &listed {
+        println!(
+            "{}{} {:<width$}  {}",
+            if device.default { '*' } else { ' ' },
+            if device.locked { 'L' } else { ' ' },
+            device.uid,
+            device.name,
+            width = width
+        );
+    }
+    Ok(())
+}
+
+fn current(backend: &dyn AudioBackend) -> Result<(), CliError> {
+    let id = backend
+        .get_default_input_device()
+        .map_err(|e| CliError::Failed(format!("no default input: {:?}", e)))?;
+    let name = backend
+        .device_name_by_id(id)
+        .unwrap_or_else(|_| "<unknown>".to_string());
+    let uid = backend
+        .device_uid_by_id(id)
+        .unwrap_or_else(|_| "<no-uid>".to_string());
+    println!("{}\t{}", uid, name);
+    Ok(())
+}
+
+/// Finds a device by exact UID, then by name ignoring case.
+pub fn find_device(backend: &dyn AudioBackend, query: &str) -> Result<DeviceInfo, CliError> {
+    let devices = devices(backend)?;
+    if let Some(device) = devices.iter().find(|d| d.uid == query) {
+        return Ok(device.clone());
+    }
+
+    let by_name: Vec<&DeviceInfo> = devices
+        .iter()
+        .filter(|d| d.name.eq_ignore_ascii_case(query))
+        .collect();
+    match by_name.as_slice() {
+        [device] => Ok((*device).clone()),
+        [] => Err(CliError::Failed(format!("no input device {}", query))),
+        several => Err(CliError::Failed(format!(
+            "{} names {} devices, use a UID: {}",
+            query,
+            several.len(),
+            several
+                .iter()
+                .map(|d| d.uid.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+fn set(backend: &dyn AudioBackend, query: &str) -> Result<(), CliError> {
+    let device = find_device(backend, query)?;
+    backend
+        .set_default_input_device(device.id)
+        .map_err(|e| CliError::Failed(format!("could not switch to {}: {:?}", device.name, e)))?;
+    println!("default input: {}", device.name);
+    Ok(())
+}
+
+/// The running agent, if any. While it runs it owns the config: it would
+/// overwrite a change made to the file behind its back, and keep enforcing
+/// the old settings.
+fn agent() -> Result<Option<control::Client>, CliError> {
+    control::Client::connect().map_err(CliError::Failed)
+}
+
+fn agent_call(agent: &mut control::Client, method: &str, params: Value) -> Result<Value, CliError> {
+    agent
+        .call(method, params)
+        .map_err(|e| CliError::Failed(format!("the agent refused {}: {}", method, e)))
+}
+
+/// Locks to `uid` and turns the lock on, like picking the device in the menu
+/// and toggling "Input Lock". Switches right away if the device is connected.
+fn lock(backend: &dyn AudioBackend, config: &ConfigStore, uid: &str) -> Result<(), CliError> {
+    if let Some(mut agent) = agent()? {
+        agent_call(&mut agent, "set_locked_uid", json!({ "uid": uid }))?;
+        let snapshot = agent_call(&mut agent, "set_enabled", json!({ "enabled": true }))?;
+        if snapshot["locked_missing"] == Value::Bool(true) {
+            println!("locked to {} (not connected)", uid);
+        } else {
+            println!("locked to {}", uid);
+        }
+        return Ok(());
+    }
+
+    config.update(|c| {
+        c.lock_enabled = true;
+        c.locked_uid = Some(uid.to_string());
+        if !c.known_uids.iter().any(|known| known == uid) {
+            c.known_uids.push(uid.to_string());
+        }
+    });
+
+    match backend.device_id_for_uid(uid) {
+        Ok(id) => {
+            backend.set_default_input_device(id).map_err(|e| {
+                CliError::Failed(format!("locked to {}, but could not switch: {:?}", uid, e))
+            })?;
+            println!("locked to {}", uid);
+        }
+        Err(_) => println!("locked to {} (not connected)", uid),
+    }
+    Ok(())
+}
+
+fn unlock(config: &ConfigStore) -> Result<(), CliError> {
+    match agent()? {
+        Some(mut agent) => {
+            agent_call(&mut agent, "set_enabled", json!({ "enabled": false }))?;
+        }
+        None => {
+            config.update(|c| c.lock_enabled = false);
+        }
+    }
+    println!("lock off");
+    Ok(())
+}
+
+fn status(backend: &dyn AudioBackend, config: &ConfigStore) -> Result<(), CliError> {
+    let cfg = config.get();
+    println!("lock:    {}", if cfg.lock_enabled { "on" } else { "off" });
+
+    match cfg.locked_uid.as_deref() {
+        Some(uid) => match backend.device_id_for_uid(uid) {
+            Ok(id) => {
+                let name = backend
+                    .device_name_by_id(id)
+                    .unwrap_or_else(|_| uid.to_string());
+                println!("locked:  {} ({})", name, uid);
+            }
+            Err(_) => println!("locked:  {} (missing)", uid),
+        },
+        None => println!("locked:  none"),
+    }
+
+    match backend.get_default_input_device() {
+        Ok(id) => {
+            let name = backend
+                .device_name_by_id(id)
+                .unwrap_or_else(|_| "<unknown>".to_string());
+            let uid = backend.device_uid_by_id(id).unwrap_or_default();
+            let busy = backend.device_is_running_somewhere(id).unwrap_or(false);
+            println!(
+                "current: {} ({}){}",
+                name,
+                uid,
+                if busy { ", in use" } else { "" }
+            );
+        }
+        Err(_) => println!("current: none"),
+    }
+    Ok(())
+}
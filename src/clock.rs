@@ -0,0 +1,60 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of "now" for the lock's timing windows.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// Wall-clock time, used by the running agent.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, for scenarios.
+#[derive(Debug)]
+pub struct VirtualClock {
+    base: Instant,
+    offset: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Time since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.offset.lock().expect("clock offset")
+    }
+
+    /// Moves the clock forward to `at`. Earlier instants are ignored.
+    pub fn advance_to(&self, at: Instant) {
+        let mut offset = self.offset.lock().expect("clock offset");
+        *offset = (*offset).max(at.saturating_duration_since(self.base));
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().expect("clock offset") += by;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.base + self.elapsed()
+    }
+}
@@ -1,15 +1,111 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub lock_enabled: bool,
     pub locked_uid: Option<String>,
     pub start_at_login: bool,
+    #[serde(default)]
+    pub known_uids: Vec<String>,
+    #[serde(default)]
+    pub ignored_uids: Vec<String>,
+    #[serde(default = "default_stability_secs")]
+    pub stability_secs: u64,
+    #[serde(default)]
+    pub stability_overrides: HashMap<String, u64>,
+    #[serde(default)]
+    pub aggressive_switching: bool,
+    #[serde(default)]
+    pub silence_monitor: bool,
+    #[serde(default = "default_silence_secs")]
+    pub silence_secs: u64,
+    #[serde(default = "default_silence_threshold_dbfs")]
+    pub silence_threshold_dbfs: f32,
+    #[serde(default)]
+    pub failover_uids: Vec<String>,
+    #[serde(default)]
+    pub trace_path: Option<PathBuf>,
+    #[serde(default)]
+    pub watchdog_secs: Option<u64>,
+    #[serde(default)]
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub log_stderr: bool,
+    #[serde(default)]
+    pub metrics_addr: Option<String>,
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub spans_path: Option<PathBuf>,
+    /// Shell commands to run on lock events, keyed by event kind.
+    #[serde(default)]
+    pub hooks: HashMap<String, String>,
+    #[serde(default = "default_hook_timeout_secs")]
+    pub hook_timeout_secs: u64,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+}
+
+/// A broker to publish the lock state to and take commands from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Topics are under `<topic_prefix>/<node_id>/`.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    /// Tells Macs on the same broker apart; the host name if not set.
+    #[serde(default)]
+    pub node_id: Option<String>,
+    /// Publish Home Assistant discovery messages.
+    #[serde(default = "default_true")]
+    pub discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_silence_secs() -> u64 {
+    10
+}
+
+fn default_silence_threshold_dbfs() -> f32 {
+    -90.0
+}
+
+fn default_stability_secs() -> u64 {
+    3
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    10
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "soundstoic".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl Default for Config {
@@ -18,6 +114,25 @@ impl Default for Config {
             lock_enabled: false,
             locked_uid: None,
             start_at_login: false,
+            known_uids: Vec::new(),
+            ignored_uids: Vec::new(),
+            stability_secs: default_stability_secs(),
+            stability_overrides: HashMap::new(),
+            aggressive_switching: false,
+            silence_monitor: false,
+            silence_secs: default_silence_secs(),
+            silence_threshold_dbfs: default_silence_threshold_dbfs(),
+            failover_uids: Vec::new(),
+            trace_path: None,
+            watchdog_secs: None,
+            log_level: None,
+            log_stderr: false,
+            metrics_addr: None,
+            otlp_endpoint: None,
+            spans_path: None,
+            hooks: HashMap::new(),
+            hook_timeout_secs: default_hook_timeout_secs(),
+            mqtt: None,
         }
     }
 }
@@ -25,18 +140,39 @@ impl Default for Config {
 pub struct ConfigStore {
     path: PathBuf,
     data: Mutex<Config>,
+    /// Why an existing config file was not used; logged once logging is up.
+    /// While it is set, changes are kept in memory only, so the file is
+    /// still there to be fixed.
+    load_error: Option<String>,
 }
 
 impl ConfigStore {
     pub fn load() -> Self {
-        let path = config_path();
-        let config = read_config(&path).unwrap_or_default();
+        Self::open(config_path())
+    }
+
+    /// The config in `path`, which need not exist yet.
+    pub fn open(path: PathBuf) -> Self {
+        let (config, load_error) = match read_config(&path) {
+            Ok(config) => (config, None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Config::default(), None),
+            Err(e) => (Config::default(), Some(e.to_string())),
+        };
         Self {
             path,
             data: Mutex::new(config),
+            load_error,
         }
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load_error(&self) -> Option<&str> {
+        self.load_error.as_deref()
+    }
+
     pub fn get(&self) -> Config {
         self.data.lock().expect("config lock").clone()
     }
@@ -47,11 +183,40 @@ impl ConfigStore {
     {
         let mut config = self.data.lock().expect("config lock");
         f(&mut config);
-        let _ = write_config(&self.path, &config);
+        if self.load_error.is_some() {
+            tracing::warn!(
+                path = %self.path.display(),
+                "not saving the config over a file that did not load"
+            );
+        } else if let Err(e) = write_config(&self.path, &config) {
+            tracing::warn!(path = %self.path.display(), error = %e, "could not save the config");
+        }
         config.clone()
     }
 }
 
+/// What a masked secret reads as.
+pub const REDACTED: &str = "<redacted>";
+
+/// Replaces every secret in a config, as JSON, with [`REDACTED`]: the MQTT
+/// credentials and each hook command, which can carry a token. Each is
+/// masked on its own, so a config that has some of them but not others, or
+/// does not parse as a `Config`, still loses all it has. Unset fields stay
+/// unset, so it shows which ones are in use.
+pub fn mask_secrets(config: &mut Value) {
+    let redacted = || Value::String(REDACTED.to_string());
+    for pointer in ["/mqtt/username", "/mqtt/password"] {
+        if let Some(secret) = config.pointer_mut(pointer).filter(|v| !v.is_null()) {
+            *secret = redacted();
+        }
+    }
+    if let Some(Value::Object(hooks)) = config.get_mut("hooks") {
+        for command in hooks.values_mut() {
+            *command = redacted();
+        }
+    }
+}
+
 fn config_path() -> PathBuf {
     let base = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
     base.join("soundstoic").join("config.json")
@@ -59,7 +224,8 @@ fn config_path() -> PathBuf {
 
 fn read_config(path: &Path) -> io::Result<Config> {
     let data = fs::read_to_string(path)?;
-    let cfg = serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let cfg =
+        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
     Ok(cfg)
 }
 
@@ -71,3 +237,49 @@ fn write_config(path: &Path, config: &Config) -> io::Result<()> {
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
     fs::write(path, data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config(name: &str, text: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("soundstoic-config-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_malformed_config_is_left_untouched() {
+        let text = r#"{ "lock_enabled": true, "hooks": { "locked": "say locked" }, "#;
+        let path = temp_config("malformed", text);
+
+        let store = ConfigStore::open(path.clone());
+        assert!(store.load_error().is_some());
+        let config = store.update(|c| c.known_uids = vec!["mic".to_string()]);
+
+        assert_eq!(config.known_uids, ["mic"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_valid_config_is_saved() {
+        let config = Config {
+            locked_uid: Some("mic".to_string()),
+            ..Config::default()
+        };
+        let path = temp_config("valid", &serde_json::to_string(&config).unwrap());
+
+        let store = ConfigStore::open(path.clone());
+        assert!(store.load_error().is_none());
+        store.update(|c| c.lock_enabled = true);
+
+        let saved = read_config(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(saved.lock_enabled);
+        assert_eq!(saved.locked_uid.as_deref(), Some("mic"));
+    }
+}
@@ -0,0 +1,668 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::{bounded, Sender};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::cli::listed_devices;
+use crate::config::ConfigStore;
+use crate::controller::{AudioEvent, Controller};
+use crate::trace::TraceEvent;
+
+/// Lines queued for a client that is not reading. A subscriber that falls
+/// this far behind is disconnected.
+const CONNECTION_QUEUE: usize = 256;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const CALL_FAILED: i64 = -32000;
+
+/// The control socket, in the runtime directory where there is one
+/// (`$XDG_RUNTIME_DIR`), otherwise next to the config file.
+pub fn socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .or_else(dirs::data_dir)
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("soundstoic")
+        .join("control.sock")
+}
+
+/// The writing end of a client connection. A thread of its own writes the
+/// queued lines in order, so replies and events never interleave, and a
+/// client that stops reading never holds up the enforcement worker.
+#[derive(Clone)]
+struct Connection {
+    lines: Sender<String>,
+    stream: Arc<UnixStream>,
+}
+
+impl Connection {
+    fn open(stream: &UnixStream) -> io::Result<Self> {
+        let mut out = stream.try_clone()?;
+        let (lines, queued) = bounded::<String>(CONNECTION_QUEUE);
+        std::thread::spawn(move || {
+            for line in queued {
+                if out.write_all(line.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            lines,
+            stream: Arc::new(stream.try_clone()?),
+        })
+    }
+
+    /// Queues a reply. Only this client's own requests wait for room.
+    fn reply(&self, line: String) -> bool {
+        self.lines.send(line).is_ok()
+    }
+
+    /// Queues an event without waiting. A client too far behind to take it
+    /// is disconnected, so it notices the gap.
+    fn notify(&self, line: &str) -> bool {
+        if self.lines.try_send(line.to_string()).is_ok() {
+            return true;
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+        false
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Serves line-delimited JSON-RPC 2.0 on a Unix socket to processes of the
+/// same user. Changes go through the same `Controller` and `ConfigStore` as
+/// the menu, and nudge the worker, which refreshes the menu.
+pub struct ControlServer {
+    controller: Arc<Controller>,
+    config: Arc<ConfigStore>,
+    events: Sender<AudioEvent>,
+    subscribers: Mutex<Vec<Connection>>,
+}
+
+impl ControlServer {
+    pub fn start(
+        path: &Path,
+        controller: Arc<Controller>,
+        config: Arc<ConfigStore>,
+        events: Sender<AudioEvent>,
+    ) -> io::Result<Arc<Self>> {
+        let listener = bind(path)?;
+        let server = Arc::new(Self {
+            controller,
+            config,
+            events,
+            subscribers: Mutex::new(Vec::new()),
+        });
+
+        let accepting = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                if !same_user(&stream) {
+                    tracing::warn!("refused a control connection from another user");
+                    continue;
+                }
+                let server = accepting.clone();
+                std::thread::spawn(move || server.serve(stream));
+            }
+        });
+        Ok(server)
+    }
+
+    /// Sends `event` to every subscribed client.
+    pub fn publish(&self, event: &AudioEvent) {
+        let mut subscribers = self.subscribers.lock().expect("subscribers");
+        if subscribers.is_empty() {
+            return;
+        }
+        let line = notification("event", json!(TraceEvent::from_event(event)));
+        subscribers.retain(|out| out.notify(&line));
+    }
+
+    fn serve(&self, stream: UnixStream) {
+        let Ok(out) = Connection::open(&stream) else {
+            return;
+        };
+        let reader = BufReader::new(stream);
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (id, result) = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    tracing::debug!(method = %request.method, "control request");
+                    let result = self.call(&request.method, &request.params, &out);
+                    // Requests without an id are notifications and get no reply.
+                    let Some(id) = request.id else { continue };
+                    (id, result)
+                }
+                Err(e) => (Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+            };
+            if !out.reply(response(id, result)) {
+                break;
+            }
+        }
+    }
+
+    fn call(&self, method: &str, params: &Value, out: &Connection) -> Result<Value, RpcError> {
+        match method {
+            "get_snapshot" => Ok(self.snapshot()),
+            "set_enabled" => {
+                let enabled = params
+                    .get("enabled")
+                    .and_then(Value::as_bool)
+                    .ok_or_else(|| RpcError::new(INVALID_PARAMS, "expected {\"enabled\": bool}"))?;
+                set_enabled(&self.controller, &self.config, enabled);
+                self.enforce_now();
+                Ok(self.snapshot())
+            }
+            "set_locked_uid" => {
+                let uid = match params.get("uid") {
+                    Some(Value::String(uid)) => Some(uid.clone()),
+                    Some(Value::Null) => None,
+                    _ => {
+                        return Err(RpcError::new(
+                            INVALID_PARAMS,
+                            "expected {\"uid\": string or null}",
+                        ))
+                    }
+                };
+                set_locked_uid(&self.controller, &self.config, uid);
+                self.enforce_now();
+                Ok(self.snapshot())
+            }
+            "get_metrics" => Ok(Value::String(self.controller.metrics_text())),
+            "list_devices" => {
+                let locked_uid = self.controller.snapshot().locked_uid;
+                let devices = listed_devices(self.controller.backend(), locked_uid.as_deref())
+                    .map_err(|e| RpcError::new(CALL_FAILED, format!("{:?}", e)))?;
+                Ok(json!(devices))
+            }
+            "subscribe" => {
+                self.subscribers
+                    .lock()
+                    .expect("subscribers")
+                    .push(out.clone());
+                Ok(json!({ "subscribed": true }))
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("no method {}", method),
+            )),
+        }
+    }
+
+    /// Applies a change right away and lets the worker follow up, as the menu
+    /// does.
+    fn enforce_now(&self) {
+        if let Err(e) = self.controller.enforce_now() {
+            tracing::warn!(error = ?e, "enforcing the lock failed");
+        }
+        let _ = self.events.send(AudioEvent::SettingsChanged);
+    }
+
+    fn snapshot(&self) -> Value {
+        let snapshot = self.controller.snapshot();
+        let backend = self.controller.backend();
+        let current = backend.get_default_input_device().ok().map(|id| {
+            json!({
+                "uid": backend.device_uid_by_id(id).ok(),
+                "name": backend.device_name_by_id(id).ok(),
+            })
+        });
+        json!({
+            "enabled": snapshot.enabled,
+            "locked_uid": snapshot.locked_uid,
+            "locked_missing": snapshot.locked_missing,
+            "current": current,
+            "pending": snapshot.pending.map(|p| json!({ "uid": p.uid, "name": p.name })),
+            "stabilizing_ms": snapshot.stabilizing.map(|d| d.as_millis() as u64),
+            "deferred": snapshot.deferred,
+            "failed_over_to": snapshot.failed_over_to,
+            "last_switch": snapshot.last_switch.map(|s| s.to_string()),
+            "switch_gave_up": snapshot.switch_gave_up,
+        })
+    }
+}
+
+/// Turns the lock on or off and saves it, as the menu does. The caller
+/// enforces.
+pub fn set_enabled(controller: &Controller, config: &ConfigStore, enabled: bool) {
+    config.update(|c| c.lock_enabled = enabled);
+    controller.set_enabled(enabled);
+}
+
+/// Locks to `uid`, which counts as approved from then on, and saves it.
+/// The caller enforces.
+pub fn set_locked_uid(controller: &Controller, config: &ConfigStore, uid: Option<String>) {
+    config.update(|c| {
+        c.locked_uid = uid.clone();
+        if let Some(uid) = uid.as_ref() {
+            if !c.known_uids.contains(uid) {
+                c.known_uids.push(uid.clone());
+            }
+        }
+    });
+    if let Some(uid) = uid.as_deref() {
+        controller.approve_device(uid);
+    }
+    controller.set_locked_uid(uid);
+}
+
+/// Binds the socket, replacing a stale one left by an agent that did not
+/// shut down, and makes it reachable by its owner only.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another agent is already listening",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+fn same_user(stream: &UnixStream) -> bool {
+    peer_uid(stream).is_ok_and(|uid| uid == unsafe { libc::geteuid() })
+}
+
+#[cfg(target_os = "macos")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let status = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if status != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> String {
+    let body = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    };
+    format!("{}\n", body)
+}
+
+fn notification(method: &str, params: Value) -> String {
+    format!(
+        "{}\n",
+        json!({ "jsonrpc": "2.0", "method": method, "params": params })
+    )
+}
+
+pub const CTL_USAGE: &str =
+    "usage: soundstoic ctl status | enable | disable | lock <uid> | devices | metrics | watch | call <method> [params]";
+
+/// `soundstoic ctl`: sends one request to the running agent and prints the
+/// result, or with `watch` prints events until interrupted.
+pub fn ctl(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (method, params) = match args.as_slice() {
+        ["status"] => ("get_snapshot", Value::Null),
+        ["enable"] => ("set_enabled", json!({ "enabled": true })),
+        ["disable"] => ("set_enabled", json!({ "enabled": false })),
+        ["lock", uid] => ("set_locked_uid", json!({ "uid": uid })),
+        ["devices"] => ("list_devices", Value::Null),
+        ["metrics"] => ("get_metrics", Value::Null),
+        ["watch"] => ("subscribe", Value::Null),
+        ["call", method] => (*method, Value::Null),
+        ["call", method, params] => (
+            *method,
+            serde_json::from_str(params).map_err(|e| format!("params: {}", e))?,
+        ),
+        _ => return Err(CTL_USAGE.to_string()),
+    };
+
+    let mut client = Client::connect()?.ok_or_else(|| {
+        format!(
+            "the agent is not running (no control socket at {})",
+            socket_path().display()
+        )
+    })?;
+    match client.call(method, params)? {
+        // Metrics come as text, ready for a scraper.
+        Value::String(text) => print!("{}", text),
+        result => println!(
+            "{}",
+            serde_json::to_string_pretty(&result).unwrap_or_default()
+        ),
+    }
+
+    if method == "subscribe" {
+        for message in client.notifications() {
+            println!("{}", message["params"]);
+        }
+    }
+    Ok(())
+}
+
+/// A connection to the running agent's control socket.
+pub struct Client {
+    stream: UnixStream,
+    lines: Lines<BufReader<UnixStream>>,
+    next_id: u64,
+}
+
+impl Client {
+    /// Connects to the running agent, or returns `None` if no agent is
+    /// listening.
+    pub fn connect() -> Result<Option<Self>, String> {
+        Self::connect_at(&socket_path())
+    }
+
+    fn connect_at(path: &Path) -> Result<Option<Self>, String> {
+        let stream = match UnixStream::connect(path) {
+            Ok(stream) => stream,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => {
+                return Err(format!(
+                    "could not reach the agent at {}: {}",
+                    path.display(),
+                    e
+                ))
+            }
+        };
+        let reader = stream.try_clone().map_err(|e| e.to_string())?;
+        Ok(Some(Self {
+            stream,
+            lines: BufReader::new(reader).lines(),
+            next_id: 1,
+        }))
+    }
+
+    /// Sends one request and waits for its result.
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        self.stream
+            .write_all(format!("{}\n", request).as_bytes())
+            .map_err(|e| e.to_string())?;
+
+        // A subscription's first event can arrive ahead of the reply.
+        let mut reply = loop {
+            let line = self
+                .lines
+                .next()
+                .ok_or("the agent closed the connection")?
+                .map_err(|e| e.to_string())?;
+            let message: Value = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+            if message.get("id").is_some() {
+                break message;
+            }
+        };
+        if let Some(error) = reply.get("error") {
+            return Err(error["message"]
+                .as_str()
+                .unwrap_or("call failed")
+                .to_string());
+        }
+        Ok(reply["result"].take())
+    }
+
+    /// Notifications, e.g. after `subscribe`, until the agent closes the
+    /// connection.
+    pub fn notifications(self) -> impl Iterator<Item = Value> {
+        self.lines
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str(&line).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crossbeam_channel::{unbounded, Receiver};
+
+    use super::*;
+    use crate::sim_backend::{SimBackend, SimDevice};
+
+    /// A control server over a simulated device model, on a socket in a
+    /// directory of its own.
+    struct Agent {
+        dir: PathBuf,
+        path: PathBuf,
+        server: Arc<ControlServer>,
+        config: Arc<ConfigStore>,
+        events: Receiver<AudioEvent>,
+    }
+
+    impl Agent {
+        fn start(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "soundstoic-control-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            let backend = Arc::new(SimBackend::new());
+            let device = |id, uid: &str, name: &str| SimDevice {
+                id,
+                uid: uid.to_string(),
+                name: name.to_string(),
+                alive: true,
+                running: false,
+            };
+            backend.load(
+                vec![
+                    device(1, "builtin", "MacBook Pro Microphone"),
+                    device(2, "usb-mic", "USB Microphone"),
+                ],
+                Some(1),
+            );
+            let config = Arc::new(ConfigStore::open(dir.join("config.json")));
+            let controller = Arc::new(Controller::from_config(backend, &config.get()));
+            let (tx, events) = unbounded();
+            let path = dir.join("control.sock");
+            let server = ControlServer::start(&path, controller, config.clone(), tx).unwrap();
+            Self {
+                dir,
+                path,
+                server,
+                config,
+                events,
+            }
+        }
+
+        fn client(&self) -> Client {
+            Client::connect_at(&self.path).unwrap().expect("listening")
+        }
+    }
+
+    impl Drop for Agent {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn lock_changes_are_applied_saved_and_reported() {
+        let agent = Agent::start("lock");
+        let mut client = agent.client();
+
+        let snapshot = client
+            .call("set_locked_uid", json!({ "uid": "usb-mic" }))
+            .unwrap();
+        assert_eq!(snapshot["locked_uid"], "usb-mic");
+        assert_eq!(snapshot["enabled"], false);
+        let snapshot = client
+            .call("set_enabled", json!({ "enabled": true }))
+            .unwrap();
+        assert_eq!(snapshot["enabled"], true);
+        assert_eq!(snapshot["current"]["uid"], "usb-mic");
+        assert_eq!(client.call("get_snapshot", Value::Null).unwrap(), snapshot);
+
+        let config = agent.config.get();
+        assert!(config.lock_enabled);
+        assert_eq!(config.locked_uid.as_deref(), Some("usb-mic"));
+        assert!(config.known_uids.contains(&"usb-mic".to_string()));
+        assert!(matches!(
+            agent.events.try_recv(),
+            Ok(AudioEvent::SettingsChanged)
+        ));
+
+        let devices = client.call("list_devices", Value::Null).unwrap();
+        let usb = devices
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|d| d["uid"] == "usb-mic")
+            .unwrap();
+        assert_eq!(usb["default"], true);
+        assert_eq!(usb["locked"], true);
+    }
+
+    #[test]
+    fn bad_requests_get_errors() {
+        let agent = Agent::start("errors");
+        let mut client = agent.client();
+
+        assert_eq!(
+            client.call("unlock_everything", Value::Null).unwrap_err(),
+            "no method unlock_everything"
+        );
+        assert_eq!(
+            client.call("set_enabled", json!({})).unwrap_err(),
+            "expected {\"enabled\": bool}"
+        );
+
+        let mut stream = UnixStream::connect(&agent.path).unwrap();
+        stream.write_all(b"not json\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        let reply: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(reply["id"], Value::Null);
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+    }
+
+    #[test]
+    fn subscribers_get_events() {
+        let agent = Agent::start("subscribe");
+        let mut client = agent.client();
+
+        assert_eq!(
+            client.call("subscribe", Value::Null).unwrap(),
+            json!({ "subscribed": true })
+        );
+        agent.server.publish(&AudioEvent::SettingsChanged);
+
+        let event = client.notifications().next().unwrap();
+        assert_eq!(event["method"], "event");
+    }
+
+    #[test]
+    fn a_subscriber_that_stops_reading_is_dropped() {
+        let agent = Agent::start("slow");
+        let mut stream = UnixStream::connect(&agent.path).unwrap();
+        stream
+            .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"subscribe\"}\n")
+            .unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream.try_clone().unwrap())
+            .read_line(&mut reply)
+            .unwrap();
+        assert!(reply.contains("subscribed"));
+
+        // Nothing is read from here on; publishing must neither block nor
+        // keep the subscriber once its queue is full.
+        let started = Instant::now();
+        for _ in 0..100_000 {
+            agent.server.publish(&AudioEvent::SettingsChanged);
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(agent.server.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_socket_is_private_to_its_owner() {
+        let agent = Agent::start("peer");
+        let mode = fs::metadata(&agent.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let err = bind(&agent.path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        let stale = agent.dir.join("stale.sock");
+        fs::write(&stale, "").unwrap();
+        assert!(bind(&stale).is_ok());
+
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        assert_eq!(peer_uid(&ours).unwrap(), unsafe { libc::geteuid() });
+        assert!(same_user(&ours));
+    }
+}
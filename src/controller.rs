@@ -1,16 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use crossbeam_channel::Receiver;
+#[cfg(target_os = "macos")]
+use crossbeam_channel::RecvTimeoutError;
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
-use crate::audio_manager::{self, AudioError};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, info_span, warn};
+
+use crate::audio_backend::{AudioBackend, AudioDeviceID, AudioError};
+#[cfg(target_os = "macos")]
+use crate::audio_manager;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+#[cfg(target_os = "macos")]
+use crate::control::ControlServer;
+#[cfg(target_os = "macos")]
+use crate::device_watcher::DeviceWatcher;
+#[cfg(target_os = "macos")]
+use crate::health_monitor::HealthMonitor;
+#[cfg(target_os = "macos")]
+use crate::history::History;
+use crate::metrics::Metrics;
+#[cfg(target_os = "macos")]
+use crate::spans;
+use crate::trace::TraceRecorder;
+#[cfg(target_os = "macos")]
 use crate::ui_notifier::UiNotifier;
 
+/// A device as it was when an event was produced. IDs are only meaningful
+/// until the audio server restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceRef {
+    pub id: AudioDeviceID,
+    pub uid: String,
+    pub name: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum AudioEvent {
-    DefaultInputChanged,
-    DevicesChanged,
+    DefaultInputChanged {
+        previous: Option<DeviceRef>,
+        current: Option<DeviceRef>,
+        at: Instant,
+    },
+    DevicesChanged {
+        at: Instant,
+    },
+    DeviceAdded {
+        device: DeviceRef,
+        at: Instant,
+    },
+    DeviceRemoved {
+        device: DeviceRef,
+        at: Instant,
+    },
     ServiceRestarted,
+    InputRunningChanged,
+    InputSilent(String),
+    InputRecovered(String),
+    DeviceDied(String),
+    StreamConfigChanged(String),
+    /// A menu action changed the lock settings.
+    SettingsChanged,
+    /// The system is about to sleep.
+    WillSleep,
+    DidWake,
+    /// Fast user switching moved away from our login session.
+    SessionInactive,
+    SessionActive,
+}
+
+impl AudioEvent {
+    /// When the HAL notification behind this event arrived, if recorded.
+    pub fn at(&self) -> Option<Instant> {
+        match self {
+            AudioEvent::DefaultInputChanged { at, .. }
+            | AudioEvent::DevicesChanged { at }
+            | AudioEvent::DeviceAdded { at, .. }
+            | AudioEvent::DeviceRemoved { at, .. } => Some(*at),
+            _ => None,
+        }
+    }
+
+    /// The device the event is about, if it names one.
+    pub fn device(&self) -> Option<&DeviceRef> {
+        match self {
+            AudioEvent::DefaultInputChanged { current, .. } => current.as_ref(),
+            AudioEvent::DeviceAdded { device, .. } | AudioEvent::DeviceRemoved { device, .. } => {
+                Some(device)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Something the lock did or noticed, for hooks and other outside observers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LockEvent {
+    pub kind: LockEventKind,
+    /// The device switched to, added or removed, or else the locked device.
+    pub uid: Option<String>,
+    /// Unknown for a locked device that is missing.
+    pub name: Option<String>,
+    /// Whether the lock is on after the event.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockEventKind {
+    /// The lock switched the default input back to its device (or the
+    /// failover device).
+    LockRestored,
+    LockedDeviceMissing,
+    LockedDeviceReturned,
+    DeviceAdded,
+    DeviceRemoved,
+    LockToggled,
+}
+
+impl LockEventKind {
+    pub const ALL: [LockEventKind; 6] = [
+        LockEventKind::LockRestored,
+        LockEventKind::LockedDeviceMissing,
+        LockEventKind::LockedDeviceReturned,
+        LockEventKind::DeviceAdded,
+        LockEventKind::DeviceRemoved,
+        LockEventKind::LockToggled,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockEventKind::LockRestored => "lock_restored",
+            LockEventKind::LockedDeviceMissing => "locked_device_missing",
+            LockEventKind::LockedDeviceReturned => "locked_device_returned",
+            LockEventKind::DeviceAdded => "device_added",
+            LockEventKind::DeviceRemoved => "device_removed",
+            LockEventKind::LockToggled => "lock_toggled",
+        }
+    }
+}
+
+impl fmt::Display for LockEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind.as_str())?;
+        if self.kind == LockEventKind::LockToggled {
+            return write!(f, " {}", if self.enabled { "on" } else { "off" });
+        }
+        if let Some(uid) = self.uid.as_ref() {
+            write!(f, " {}", uid)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingDevice {
+    pub uid: String,
+    pub name: String,
+}
+
+/// How a switch of the default input turned out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwitchOutcome {
+    /// The default read back as the device the lock switched to.
+    Applied,
+    /// The set succeeded, but the default had moved elsewhere by the time it
+    /// was read back.
+    RevertedBySystem,
+    Failed(AudioError),
+}
+
+impl fmt::Display for SwitchOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwitchOutcome::Applied => write!(f, "applied"),
+            SwitchOutcome::RevertedBySystem => write!(f, "reverted by the system"),
+            SwitchOutcome::Failed(AudioError::OsStatus(status)) => {
+                write!(f, "failed (OSStatus {})", status)
+            }
+            SwitchOutcome::Failed(AudioError::NotFound) => write!(f, "failed (device not found)"),
+        }
+    }
+}
+
+/// The lock's most recent switch while it is being verified or retried.
+#[derive(Debug, Clone, Copy)]
+struct Switch {
+    target: AudioDeviceID,
+    attempts: u32,
+    /// When to read the default back after a successful set.
+    verify_at: Option<Instant>,
+    /// No new attempt before this, after a failure or revert.
+    retry_at: Option<Instant>,
+}
+
+impl Switch {
+    fn gave_up(&self, now: Instant) -> bool {
+        self.attempts >= MAX_SWITCH_ATTEMPTS && self.retry_at.is_some_and(|at| now < at)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -18,6 +209,13 @@ pub struct LockSnapshot {
     pub enabled: bool,
     pub locked_uid: Option<String>,
     pub locked_missing: bool,
+    pub pending: Option<PendingDevice>,
+    pub stabilizing: Option<Duration>,
+    pub deferred: bool,
+    pub failed_over_to: Option<String>,
+    pub last_switch: Option<SwitchOutcome>,
+    /// Retries are used up; the lock waits before switching again.
+    pub switch_gave_up: bool,
 }
 
 #[derive(Debug)]
@@ -25,114 +223,1129 @@ struct LockState {
     enabled: bool,
     locked_uid: Option<String>,
     locked_missing: bool,
-    last_self_set: Option<(crate::audio_sys::AudioDeviceID, Instant)>,
+    last_self_set: Option<(AudioDeviceID, Instant)>,
+    known_uids: HashSet<String>,
+    ignored_uids: HashSet<String>,
+    last_known_default: Option<AudioDeviceID>,
+    pending: Option<PendingDevice>,
+    stability: Duration,
+    stability_overrides: HashMap<String, Duration>,
+    locked_returning: bool,
+    locked_stable_since: Option<Instant>,
+    stabilizing: Option<Duration>,
+    aggressive: bool,
+    deferred: bool,
+    failover_uids: Vec<String>,
+    locked_silent: bool,
+    failed_over_to: Option<String>,
+    switch: Option<Switch>,
+    last_switch: Option<SwitchOutcome>,
 }
 
 #[derive(Debug, Default)]
 pub struct EnforceResult {
     pub changed: bool,
     pub locked_missing: bool,
+    pub pending_approval: bool,
+    pub recheck_after: Option<Duration>,
+    pub deferred: bool,
+    pub failed_over_to: Option<String>,
+    /// Outcome of a switch that was verified or attempted in this pass.
+    pub switch: Option<SwitchOutcome>,
 }
 
+impl EnforceResult {
+    /// Asks for another pass after `delay`, keeping any earlier request.
+    fn recheck_within(&mut self, delay: Duration) {
+        self.recheck_after = Some(self.recheck_after.map_or(delay, |d| d.min(delay)));
+    }
+}
+
+/// How long the worker collects HAL notifications before acting on them.
+pub const DEBOUNCE: Duration = Duration::from_millis(180);
+
+/// After this many watchdog checks in a row find the lock out of place, the
+/// worker re-installs the device watcher.
+pub const WATCHDOG_REINSTALL_AFTER: u32 = 2;
+
+/// After waking from sleep or returning to our login session, devices
+/// reconnect and macOS may pick a default input of its own. The worker waits
+/// this long before enforcing again.
+pub const WAKE_SETTLE: Duration = Duration::from_secs(2);
+
+/// After the audio server restarts, devices reappear one by one under new
+/// IDs. The worker waits this long before enforcing again.
+pub const SERVICE_SETTLE: Duration = Duration::from_secs(1);
+
+/// After switching the default input itself, the lock does not switch to the
+/// same device again for this long.
+const SELF_SET_WINDOW: Duration = Duration::from_millis(350);
+
+/// How long after a switch the default is read back to verify it stuck.
+const SWITCH_SETTLE: Duration = Duration::from_millis(300);
+/// First delay before retrying a failed or reverted switch; doubles with
+/// each attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_SWITCH_ATTEMPTS: u32 = 4;
+/// After the attempts are used up, the lock waits this long (or for the
+/// devices or settings to change) before trying again.
+const GIVE_UP_COOLDOWN: Duration = Duration::from_secs(30);
+
 pub struct Controller {
+    backend: Arc<dyn AudioBackend>,
+    clock: Arc<dyn Clock>,
     state: Mutex<LockState>,
+    metrics: Metrics,
+    subscribers: Mutex<Vec<Sender<LockEvent>>>,
+    /// Where `enforce_now` records its decisions; the worker records its
+    /// own.
+    recorder: Option<Arc<TraceRecorder>>,
 }
 
 impl Controller {
-    pub fn new(enabled: bool, locked_uid: Option<String>) -> Self {
+    pub fn new(backend: Arc<dyn AudioBackend>, enabled: bool, locked_uid: Option<String>) -> Self {
         Self {
+            backend,
+            clock: Arc::new(SystemClock),
             state: Mutex::new(LockState {
                 enabled,
                 locked_uid,
                 locked_missing: false,
                 last_self_set: None,
+                known_uids: HashSet::new(),
+                ignored_uids: HashSet::new(),
+                last_known_default: None,
+                pending: None,
+                stability: Duration::ZERO,
+                stability_overrides: HashMap::new(),
+                locked_returning: false,
+                locked_stable_since: None,
+                stabilizing: None,
+                aggressive: false,
+                deferred: false,
+                failover_uids: Vec::new(),
+                locked_silent: false,
+                failed_over_to: None,
+                switch: None,
+                last_switch: None,
             }),
+            metrics: Metrics::default(),
+            subscribers: Mutex::new(Vec::new()),
+            recorder: None,
         }
     }
 
+    /// Records the decisions made by `enforce_now` into the event trace.
+    pub fn with_recorder(mut self, recorder: Option<Arc<TraceRecorder>>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Replaces the wall clock, e.g. with a `VirtualClock` in scenarios.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// A controller with every lock setting taken from `cfg`.
+    pub fn from_config(backend: Arc<dyn AudioBackend>, cfg: &Config) -> Self {
+        let controller = Self::new(backend, cfg.lock_enabled, cfg.locked_uid.clone());
+        controller.set_catalog(&cfg.known_uids, &cfg.ignored_uids);
+        controller.set_aggressive(cfg.aggressive_switching);
+        controller.set_failover_uids(cfg.failover_uids.clone());
+        controller.set_stability(
+            Duration::from_secs(cfg.stability_secs),
+            cfg.stability_overrides
+                .iter()
+                .map(|(uid, secs)| (uid.clone(), Duration::from_secs(*secs)))
+                .collect(),
+        );
+        controller
+    }
+
+    pub fn backend(&self) -> &dyn AudioBackend {
+        self.backend.as_ref()
+    }
+
+    /// The metrics in the Prometheus text format, with the lock state and
+    /// device count read now.
+    pub fn metrics_text(&self) -> String {
+        let devices = self.backend.list_input_devices().ok().map(|d| d.len());
+        self.metrics
+            .render(&self.snapshot(), devices, self.clock.now())
+    }
+
     pub fn snapshot(&self) -> LockSnapshot {
         let state = self.state.lock().expect("lock state");
         LockSnapshot {
             enabled: state.enabled,
             locked_uid: state.locked_uid.clone(),
             locked_missing: state.locked_missing,
+            pending: state.pending.clone(),
+            stabilizing: state.stabilizing,
+            deferred: state.deferred,
+            failed_over_to: state.failed_over_to.clone(),
+            last_switch: state.last_switch.clone(),
+            switch_gave_up: state.switch.is_some_and(|s| s.gave_up(self.clock.now())),
+        }
+    }
+
+    /// Lock events from now on. Subscribers that are dropped are forgotten.
+    pub fn subscribe(&self) -> Receiver<LockEvent> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().expect("subscribers").push(tx);
+        rx
+    }
+
+    fn emit(&self, kind: LockEventKind, uid: Option<String>, name: Option<String>) {
+        let mut subscribers = self.subscribers.lock().expect("subscribers");
+        if subscribers.is_empty() {
+            return;
         }
+        let event = LockEvent {
+            kind,
+            uid,
+            name,
+            enabled: self.state.lock().expect("lock state").enabled,
+        };
+        debug!(%event, "lock event");
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// The name of the device with `uid`, if it is connected.
+    fn name_for_uid(&self, uid: &str) -> Option<String> {
+        let id = self.backend.device_id_for_uid(uid).ok()?;
+        self.backend.device_name_by_id(id).ok()
     }
 
     pub fn set_enabled(&self, enabled: bool) {
-        let mut state = self.state.lock().expect("lock state");
-        state.enabled = enabled;
+        let (changed, locked_uid) = {
+            let mut state = self.state.lock().expect("lock state");
+            let changed = state.enabled != enabled;
+            state.enabled = enabled;
+            state.switch = None;
+            (changed, state.locked_uid.clone())
+        };
+        if changed {
+            let name = locked_uid.as_deref().and_then(|uid| self.name_for_uid(uid));
+            self.emit(LockEventKind::LockToggled, locked_uid, name);
+        }
     }
 
     pub fn set_locked_uid(&self, uid: Option<String>) {
         let mut state = self.state.lock().expect("lock state");
         state.locked_uid = uid;
         state.locked_missing = false;
+        state.locked_returning = false;
+        state.locked_stable_since = None;
+        state.stabilizing = None;
+        state.locked_silent = false;
+        state.failed_over_to = None;
+        state.switch = None;
+    }
+
+    /// Devices to fall back to, in order, while the locked device is silent.
+    pub fn set_failover_uids(&self, uids: Vec<String>) {
+        let mut state = self.state.lock().expect("lock state");
+        state.failover_uids = uids;
+    }
+
+    /// The locked device while the lock is on and the device is present, for
+    /// per-device listeners and the health monitor to follow.
+    pub fn locked_input(&self) -> Option<(AudioDeviceID, String)> {
+        let (enabled, locked_uid) = {
+            let state = self.state.lock().expect("lock state");
+            (state.enabled, state.locked_uid.clone())
+        };
+        if !enabled {
+            return None;
+        }
+        let uid = locked_uid?;
+        let id = self.backend.device_id_for_uid(&uid).ok()?;
+        Some((id, uid))
+    }
+
+    /// Whether the default input becoming `device_id` at `at` was the lock's
+    /// own switch. The HAL may report it a moment before or after `set`
+    /// returns.
+    pub fn is_own_switch(&self, device_id: AudioDeviceID, at: Instant) -> bool {
+        let state = self.state.lock().expect("lock state");
+        state.last_self_set.is_some_and(|(id, set_at)| {
+            let gap = at
+                .saturating_duration_since(set_at)
+                .max(set_at.saturating_duration_since(at));
+            id == device_id && gap < SELF_SET_WINDOW
+        })
+    }
+
+    /// Applies state carried by an event before the next `enforce`.
+    pub fn handle_event(&self, event: &AudioEvent) {
+        if let AudioEvent::DefaultInputChanged { current, at, .. } = event {
+            let own = current
+                .as_ref()
+                .is_some_and(|d| self.is_own_switch(d.id, *at));
+            if !own && self.state.lock().expect("lock state").enabled {
+                self.metrics.default_moved(*at);
+            }
+        }
+        if let AudioEvent::DeviceAdded { device, .. } | AudioEvent::DeviceRemoved { device, .. } =
+            event
+        {
+            let kind = match event {
+                AudioEvent::DeviceAdded { .. } => LockEventKind::DeviceAdded,
+                _ => LockEventKind::DeviceRemoved,
+            };
+            self.emit(kind, Some(device.uid.clone()), Some(device.name.clone()));
+        }
+
+        let mut state = self.state.lock().expect("lock state");
+        match event {
+            AudioEvent::InputSilent(uid) if state.locked_uid.as_ref() == Some(uid) => {
+                state.locked_silent = true;
+            }
+            AudioEvent::InputRecovered(uid) if state.locked_uid.as_ref() == Some(uid) => {
+                state.locked_silent = false;
+            }
+            AudioEvent::DeviceDied(uid) if state.locked_uid.as_ref() == Some(uid) => {
+                // If it comes back to life it has to prove itself stable first.
+                state.locked_returning = true;
+                state.locked_stable_since = None;
+            }
+            AudioEvent::DefaultInputChanged {
+                previous, current, ..
+            } => {
+                // The device that was default before a hijack is the one to go
+                // back to.
+                for device in [previous, current].into_iter().flatten() {
+                    if state.known_uids.contains(&device.uid) {
+                        state.last_known_default = Some(device.id);
+                    }
+                }
+
+                if let Some(device) = current {
+                    let unknown = !state.known_uids.contains(&device.uid)
+                        && !state.ignored_uids.contains(&device.uid);
+                    if unknown && state.enabled && state.pending.is_none() {
+                        state.pending = Some(PendingDevice {
+                            uid: device.uid.clone(),
+                            name: device.name.clone(),
+                        });
+                    }
+                }
+            }
+            AudioEvent::DeviceAdded { device, at }
+                if state.locked_returning && state.locked_uid.as_ref() == Some(&device.uid) =>
+            {
+                // The stability window starts when the device showed up, not
+                // when the debounced worker first looks at it.
+                state.locked_stable_since = Some(*at);
+            }
+            AudioEvent::DevicesChanged { .. } | AudioEvent::SettingsChanged
+                if state.switch.is_some_and(|s| s.gave_up(self.clock.now())) =>
+            {
+                // Something changed, so the switch is worth another round.
+                state.switch = None;
+            }
+            AudioEvent::DidWake | AudioEvent::SessionActive => {
+                // Whatever the lock was retrying or had given up on happened
+                // in a different setup.
+                state.last_self_set = None;
+                state.switch = None;
+            }
+            AudioEvent::ServiceRestarted => {
+                // Every device ID held from before the restart is stale and
+                // may now name a different device.
+                state.last_self_set = None;
+                state.last_known_default = None;
+                state.switch = None;
+            }
+            AudioEvent::DeviceRemoved { device, .. } => {
+                if state.pending.as_ref().is_some_and(|p| p.uid == device.uid) {
+                    state.pending = None;
+                }
+                if state.last_known_default == Some(device.id) {
+                    state.last_known_default = None;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// How long a returning locked device must stay present and alive before
+    /// the lock switches back to it. `overrides` is keyed by device UID.
+    pub fn set_stability(&self, window: Duration, overrides: HashMap<String, Duration>) {
+        let mut state = self.state.lock().expect("lock state");
+        state.stability = window;
+        state.stability_overrides = overrides;
+    }
+
+    /// When set, the lock switches devices even while the current input is
+    /// in use by another process.
+    pub fn set_aggressive(&self, aggressive: bool) {
+        let mut state = self.state.lock().expect("lock state");
+        state.aggressive = aggressive;
+    }
+
+    pub fn set_catalog(&self, known: &[String], ignored: &[String]) {
+        let mut state = self.state.lock().expect("lock state");
+        state.known_uids = known.iter().cloned().collect();
+        state.ignored_uids = ignored.iter().cloned().collect();
+    }
+
+    /// Marks `uid` as known and clears any approval prompt for it.
+    pub fn approve_device(&self, uid: &str) {
+        let mut state = self.state.lock().expect("lock state");
+        state.known_uids.insert(uid.to_string());
+        state.ignored_uids.remove(uid);
+        if state.pending.as_ref().is_some_and(|p| p.uid == uid) {
+            state.pending = None;
+        }
+    }
+
+    /// Approves `uid` and makes it the default input, verified and retried
+    /// like the lock's own switches.
+    pub fn allow_device(&self, uid: &str) -> Result<EnforceResult, AudioError> {
+        self.approve_device(uid);
+        let id = self.backend.device_id_for_uid(uid)?;
+        let mut result = EnforceResult::default();
+        self.switch_default(id, &mut result);
+        Ok(result)
+    }
+
+    /// Keeps reverting away from `uid` without prompting again.
+    pub fn ignore_device(&self, uid: &str) {
+        let mut state = self.state.lock().expect("lock state");
+        state.ignored_uids.insert(uid.to_string());
+        if state.pending.as_ref().is_some_and(|p| p.uid == uid) {
+            state.pending = None;
+        }
+    }
+
+    /// Enforces right away rather than through the worker: at startup, and
+    /// after a change from the menu, the control socket or MQTT. The decision
+    /// goes into the trace like the worker's, so a replay makes it too.
+    pub fn enforce_now(&self) -> Result<EnforceResult, AudioError> {
+        let Some(recorder) = self.recorder.as_ref() else {
+            return self.enforce();
+        };
+        let before = self.snapshot();
+        recorder.devices(self.backend.as_ref());
+        let result = self.enforce();
+        recorder.immediate_decision(&before, &result, self.backend.as_ref());
+        result
     }
 
     pub fn enforce(&self) -> Result<EnforceResult, AudioError> {
-        let (enabled, locked_uid, last_self_set) = {
+        let span = info_span!(
+            "enforce",
+            changed = tracing::field::Empty,
+            switch = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        let was_missing = self.state.lock().expect("lock state").locked_missing;
+        let result = self.enforce_pass();
+        if let Ok(result) = result.as_ref() {
+            span.record("changed", result.changed);
+            if let Some(switch) = result.switch.as_ref() {
+                span.record("switch", tracing::field::display(switch));
+            }
+        }
+        let (enabled, locked_uid, missing) = {
             let state = self.state.lock().expect("lock state");
-            (state.enabled, state.locked_uid.clone(), state.last_self_set)
+            (
+                state.enabled,
+                state.locked_uid.clone(),
+                state.locked_missing,
+            )
         };
+        self.metrics
+            .record_enforce(&result, enabled && missing, self.clock.now());
+        if let Ok(result) = result.as_ref() {
+            self.emit_enforce_events(result, enabled, locked_uid, was_missing, missing);
+        }
+        result
+    }
+
+    /// Lock events for what an `enforce` pass changed.
+    fn emit_enforce_events(
+        &self,
+        result: &EnforceResult,
+        enabled: bool,
+        locked_uid: Option<String>,
+        was_missing: bool,
+        missing: bool,
+    ) {
+        if enabled && missing != was_missing {
+            let (kind, name) = if missing {
+                (LockEventKind::LockedDeviceMissing, None)
+            } else {
+                let name = locked_uid.as_deref().and_then(|uid| self.name_for_uid(uid));
+                (LockEventKind::LockedDeviceReturned, name)
+            };
+            self.emit(kind, locked_uid, name);
+        }
+        if result.switch == Some(SwitchOutcome::Applied) {
+            let current = self.backend.get_default_input_device().ok();
+            let uid = current.and_then(|id| self.backend.device_uid_by_id(id).ok());
+            let name = current.and_then(|id| self.backend.device_name_by_id(id).ok());
+            self.emit(LockEventKind::LockRestored, uid, name);
+        }
+    }
 
+    fn enforce_pass(&self) -> Result<EnforceResult, AudioError> {
         let mut result = EnforceResult::default();
+        let enabled = {
+            let mut state = self.state.lock().expect("lock state");
+            state.deferred = false;
+            state.enabled
+        };
+        self.verify_switch(&mut result)?;
+        self.enforce_lock(&mut result)?;
+        // With the lock off, the default input is the user's to change.
+        if enabled {
+            self.screen_default_input(&mut result)?;
+        }
+        Ok(result)
+    }
+
+    fn enforce_lock(&self, result: &mut EnforceResult) -> Result<(), AudioError> {
+        let (enabled, locked_uid, last_self_set) = {
+            let state = self.state.lock().expect("lock state");
+            (state.enabled, state.locked_uid.clone(), state.last_self_set)
+        };
 
         if !enabled {
-            return Ok(result);
+            return Ok(());
         }
 
         let Some(locked_uid) = locked_uid else {
-            return Ok(result);
+            return Ok(());
         };
 
-        let locked_id = match audio_manager::device_id_for_uid(&locked_uid) {
+        // Only `NotFound` means the device is gone. Any other failure is the
+        // HAL's, and is reported rather than shown as a missing device.
+        let locked_id = match self.backend.device_id_for_uid(&locked_uid) {
             Ok(id) => id,
-            Err(_) => {
+            Err(AudioError::NotFound) => {
                 let mut state = self.state.lock().expect("lock state");
                 state.locked_missing = true;
+                state.locked_returning = true;
+                state.locked_stable_since = None;
+                state.stabilizing = None;
                 result.locked_missing = true;
-                return Ok(result);
+                debug!(uid = %locked_uid, "locked device is missing");
+                return Ok(());
             }
+            Err(e) => return Err(e),
         };
 
+        if let Some(remaining) = self.stability_remaining(&locked_uid, locked_id) {
+            debug!(
+                uid = %locked_uid,
+                device_id = locked_id,
+                remaining_ms = remaining.as_millis() as u64,
+                "waiting for the returning device to settle"
+            );
+            result.recheck_within(remaining.min(Duration::from_secs(1)));
+            return Ok(());
+        }
+
+        let failover = self.failover_target(locked_id);
+        result.failed_over_to = failover.as_ref().map(|(_, uid)| uid.clone());
+        let target_id = failover.as_ref().map(|(id, _)| *id).unwrap_or(locked_id);
+
         if let Some((id, when)) = last_self_set {
-            if id == locked_id && when.elapsed() < Duration::from_millis(350) {
-                return Ok(result);
+            let since = self.clock.now().saturating_duration_since(when);
+            if id == target_id && since < SELF_SET_WINDOW {
+                // Events right after our own switch are mostly its echo, but a
+                // real hijack can land in the window too, so look again once
+                // it has passed.
+                debug!(device_id = id, "inside our own switch's echo window");
+                result.recheck_within(SELF_SET_WINDOW - since);
+                return Ok(());
+            }
+        }
+
+        let current = self.backend.get_default_input_device()?;
+        if current != target_id {
+            // A silent locked device is what the call is already stuck on, so
+            // failover does not wait for it to go idle.
+            if failover.is_none() && self.hold_off_switch(current, result) {
+                return Ok(());
             }
+            self.switch_default(target_id, result);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the default back once the last switch has had time to settle,
+    /// and schedules a retry if it did not stick.
+    fn verify_switch(&self, result: &mut EnforceResult) -> Result<(), AudioError> {
+        let now = self.clock.now();
+        let (target, verify_at) = {
+            let state = self.state.lock().expect("lock state");
+            match state.switch {
+                Some(Switch {
+                    target,
+                    verify_at: Some(at),
+                    ..
+                }) => (target, at),
+                _ => return Ok(()),
+            }
+        };
+
+        if now < verify_at {
+            result.recheck_within(verify_at - now);
+            return Ok(());
+        }
+
+        let current = self.backend.get_default_input_device()?;
+        let mut state = self.state.lock().expect("lock state");
+        let Some(switch) = state.switch.as_mut() else {
+            return Ok(());
+        };
+        switch.verify_at = None;
+
+        let outcome = if current == target {
+            state.switch = None;
+            SwitchOutcome::Applied
+        } else {
+            warn!(
+                target_id = target,
+                current_id = current,
+                attempts = switch.attempts,
+                "switch did not stick"
+            );
+            let delay = retry_delay(switch.attempts);
+            switch.retry_at = Some(now + delay);
+            result.recheck_within(delay);
+            SwitchOutcome::RevertedBySystem
+        };
+        state.last_switch = Some(outcome.clone());
+        result.switch = Some(outcome);
+        Ok(())
+    }
+
+    /// Makes `target` the default input, unless an earlier switch to it is
+    /// still settling, backing off, or out of attempts.
+    fn switch_default(&self, target: AudioDeviceID, result: &mut EnforceResult) {
+        let now = self.clock.now();
+        let attempts = {
+            let state = self.state.lock().expect("lock state");
+            match state.switch.filter(|s| s.target == target) {
+                Some(switch) if switch.verify_at.is_some() => return,
+                Some(switch) => match switch.retry_at {
+                    Some(at) if now < at => {
+                        result.recheck_within(at - now);
+                        return;
+                    }
+                    _ if switch.attempts >= MAX_SWITCH_ATTEMPTS => {
+                        info!(target_id = target, "cooldown over, trying the switch again");
+                        0
+                    }
+                    _ => switch.attempts,
+                },
+                None => 0,
+            }
+        };
+
+        let set = self.backend.set_default_input_device(target);
+        let mut state = self.state.lock().expect("lock state");
+        let attempts = attempts + 1;
+        match set {
+            Ok(()) => {
+                info!(
+                    target_id = target,
+                    attempt = attempts,
+                    "switched the default input"
+                );
+                state.last_self_set = Some((target, now));
+                state.switch = Some(Switch {
+                    target,
+                    attempts,
+                    verify_at: Some(now + SWITCH_SETTLE),
+                    retry_at: None,
+                });
+                result.changed = true;
+                result.recheck_within(SWITCH_SETTLE);
+            }
+            Err(e) => {
+                warn!(target_id = target, attempt = attempts, error = ?e, "could not switch the default input");
+                let delay = retry_delay(attempts);
+                state.switch = Some(Switch {
+                    target,
+                    attempts,
+                    verify_at: None,
+                    retry_at: Some(now + delay),
+                });
+                state.last_switch = Some(SwitchOutcome::Failed(e.clone()));
+                result.switch = Some(SwitchOutcome::Failed(e));
+                result.recheck_within(delay);
+            }
+        }
+    }
+
+    /// While the locked device is reported silent, picks the first configured
+    /// failover device that is present.
+    fn failover_target(&self, locked_id: AudioDeviceID) -> Option<(AudioDeviceID, String)> {
+        let (silent, candidates) = {
+            let state = self.state.lock().expect("lock state");
+            (state.locked_silent, state.failover_uids.clone())
+        };
+
+        let target = if silent {
+            candidates.into_iter().find_map(|uid| {
+                let id = self.backend.device_id_for_uid(&uid).ok()?;
+                (id != locked_id).then_some((id, uid))
+            })
+        } else {
+            None
+        };
+
+        let mut state = self.state.lock().expect("lock state");
+        let failed_over_to = target.as_ref().map(|(_, uid)| uid.clone());
+        if failed_over_to != state.failed_over_to {
+            info!(locked_id, failover_uid = ?failed_over_to, "failover target changed");
+        }
+        state.failed_over_to = failed_over_to;
+        target
+    }
+
+    /// Switching the default input while it is capturing (e.g. in a call) can
+    /// drop the call's audio, so wait until the device goes idle unless the
+    /// aggressive setting is on.
+    fn hold_off_switch(&self, current: AudioDeviceID, result: &mut EnforceResult) -> bool {
+        let running = self
+            .backend
+            .device_is_running_somewhere(current)
+            .unwrap_or(false)
+            && self.captured_by_others(current);
+        let mut state = self.state.lock().expect("lock state");
+        if !running || state.aggressive {
+            return false;
+        }
+        debug!(
+            device_id = current,
+            "default input is in use, deferring the switch"
+        );
+        state.deferred = true;
+        result.deferred = true;
+        true
+    }
+
+    /// Whether a process other than this one captures from a running device,
+    /// so the silence monitor's own stream never holds off a switch. Without
+    /// a process list (before macOS 14) any running device counts.
+    fn captured_by_others(&self, device_id: AudioDeviceID) -> bool {
+        match self.backend.list_processes() {
+            Ok(processes) if !processes.is_empty() => processes
+                .iter()
+                .any(|p| p.is_running_input && !p.is_self() && p.devices.contains(&device_id)),
+            _ => true,
+        }
+    }
+
+    /// Returns how much longer a locked device that just came back has to stay
+    /// alive before it is trusted again, or `None` once it is stable.
+    fn stability_remaining(&self, uid: &str, device_id: AudioDeviceID) -> Option<Duration> {
+        let alive = self.backend.device_is_alive(device_id).unwrap_or(true);
+
+        let mut state = self.state.lock().expect("lock state");
+        state.locked_missing = false;
+        if !state.locked_returning {
+            return None;
+        }
+
+        let window = state
+            .stability_overrides
+            .get(uid)
+            .copied()
+            .unwrap_or(state.stability);
+
+        if !alive {
+            state.locked_stable_since = None;
+            state.stabilizing = Some(window);
+            return Some(window);
         }
 
-        let current = audio_manager::get_default_input_device()?;
-        if current != locked_id {
-            audio_manager::set_default_input_device(locked_id)?;
+        let now = self.clock.now();
+        let since = *state.locked_stable_since.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(since);
+        if elapsed >= window {
+            state.locked_returning = false;
+            state.locked_stable_since = None;
+            state.stabilizing = None;
+            return None;
+        }
+
+        let remaining = window - elapsed;
+        state.stabilizing = Some(remaining);
+        Some(remaining)
+    }
+
+    /// Reverts the default input when macOS switches to a device that is not
+    /// in the catalog, and records it so the menu can ask what to do.
+    fn screen_default_input(&self, result: &mut EnforceResult) -> Result<(), AudioError> {
+        let current = self.backend.get_default_input_device()?;
+        let Ok(uid) = self.backend.device_uid_by_id(current) else {
+            return Ok(());
+        };
+
+        let (prompt, last_known) = {
             let mut state = self.state.lock().expect("lock state");
-            state.last_self_set = Some((locked_id, Instant::now()));
-            state.locked_missing = false;
-            result.changed = true;
+            if state.known_uids.contains(&uid) {
+                state.last_known_default = Some(current);
+                return Ok(());
+            }
+            let ignored = state.ignored_uids.contains(&uid);
+            let already_pending = state.pending.as_ref().is_some_and(|p| p.uid == uid);
+            (
+                !ignored && !already_pending,
+                state.last_known_default.filter(|id| *id != current),
+            )
+        };
+
+        // The HAL is asked outside the state lock.
+        let name = prompt.then(|| {
+            self.backend
+                .device_name_by_id(current)
+                .unwrap_or_else(|_| uid.clone())
+        });
+        let last_known =
+            last_known.and_then(|id| Some((id, self.backend.device_uid_by_id(id).ok()?)));
+
+        let fallback = {
+            let mut state = self.state.lock().expect("lock state");
+            if let Some(name) = name {
+                info!(device_id = current, uid = %uid, name = %name, "unknown device became the default input");
+                state.pending = Some(PendingDevice { uid, name });
+                result.pending_approval = true;
+            }
+            last_known
+                .filter(|(_, uid)| state.known_uids.contains(uid))
+                .map(|(id, _)| id)
+        };
+
+        if let Some(fallback) = fallback {
+            if self.hold_off_switch(current, result) {
+                return Ok(());
+            }
+            self.switch_default(fallback, result);
         }
 
-        Ok(result)
+        Ok(())
     }
 }
 
-pub fn run_enforcement_worker(
-    rx: Receiver<AudioEvent>,
+/// Backoff before the next attempt once `attempts` switches have not stuck,
+/// or the cooldown once they are used up.
+fn retry_delay(attempts: u32) -> Duration {
+    if attempts >= MAX_SWITCH_ATTEMPTS {
+        GIVE_UP_COOLDOWN
+    } else {
+        RETRY_BACKOFF * 2u32.pow(attempts.saturating_sub(1))
+    }
+}
+
+/// What a watchdog check found when the lock had to act without having been
+/// told anything had changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogFinding {
+    /// A notification was missed; `enforce` has already corrected it.
+    MissedNotification,
+    /// Notifications have been missed repeatedly; the listeners should be
+    /// installed again.
+    Reinstall,
+}
+
+/// The debounce and recheck timing of the enforcement worker, kept apart from
+/// threads and channels so scenarios can drive it on a virtual clock.
+pub struct WorkerCore {
     controller: Arc<Controller>,
-    ui: UiNotifier,
-) {
-    std::thread::spawn(move || loop {
-        if rx.recv().is_err() {
-            break;
+    batch_started: Option<Instant>,
+    recheck_at: Option<Instant>,
+    /// No enforcement before this, while the audio server or devices settle.
+    hold_until: Option<Instant>,
+    /// Nothing is enforced while asleep or in another user's session. The
+    /// two are tracked apart, since waking up does not end the other
+    /// session.
+    asleep: bool,
+    session_inactive: bool,
+    watchdog: Option<Duration>,
+    watchdog_at: Option<Instant>,
+    /// Watchdog checks in a row that found drift.
+    drift_streak: u32,
+    finding: Option<WatchdogFinding>,
+}
+
+impl WorkerCore {
+    pub fn new(controller: Arc<Controller>) -> Self {
+        Self {
+            controller,
+            batch_started: None,
+            recheck_at: None,
+            hold_until: None,
+            asleep: false,
+            session_inactive: false,
+            watchdog: None,
+            watchdog_at: None,
+            drift_streak: 0,
+            finding: None,
+        }
+    }
+
+    /// Also enforces every `interval` when nothing else has, as a safety net
+    /// for HAL notifications that never arrive.
+    pub fn with_watchdog(mut self, interval: Option<Duration>, now: Instant) -> Self {
+        self.watchdog = interval;
+        self.watchdog_at = interval.map(|interval| now + interval);
+        self
+    }
+
+    pub fn controller(&self) -> &Arc<Controller> {
+        &self.controller
+    }
+
+    /// Applies `event` and starts a debounce window if none is open. The
+    /// window starts when the HAL notification arrived, not when the worker
+    /// got to it.
+    pub fn on_event(&mut self, event: &AudioEvent, now: Instant) {
+        self.controller.handle_event(event);
+        if self.batch_started.is_none() {
+            self.batch_started = Some(event.at().unwrap_or(now));
+        }
+        let settle = match event {
+            AudioEvent::ServiceRestarted => Some(SERVICE_SETTLE),
+            AudioEvent::DidWake | AudioEvent::SessionActive => Some(WAKE_SETTLE),
+            _ => None,
+        };
+        if let Some(settle) = settle {
+            self.hold_until = self.hold_until.max(Some(now + settle));
         }
+        match event {
+            AudioEvent::WillSleep => self.asleep = true,
+            AudioEvent::DidWake => self.asleep = false,
+            AudioEvent::SessionInactive => self.session_inactive = true,
+            AudioEvent::SessionActive => self.session_inactive = false,
+            _ => {}
+        }
+    }
+
+    /// When the worker next has to run `enforce`, if anything is pending. A
+    /// recheck (e.g. a stability countdown) counts even without new events.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.asleep || self.session_inactive {
+            return None;
+        }
+        let batch_end = self.batch_started.map(|start| start + DEBOUNCE);
+        let deadline = [batch_end, self.recheck_at, self.watchdog_at]
+            .into_iter()
+            .flatten()
+            .min();
+        match (deadline, self.hold_until) {
+            (Some(deadline), Some(hold)) => Some(deadline.max(hold)),
+            (deadline, _) => deadline,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next_deadline().is_some_and(|deadline| now >= deadline)
+    }
+
+    /// Runs `enforce` and schedules the recheck it asks for.
+    pub fn run(&mut self, now: Instant) -> Result<EnforceResult, AudioError> {
+        // Only the watchdog is due: nothing has reported a change.
+        let watchdog_check = self.batch_started.is_none()
+            && self.recheck_at.is_none_or(|at| at > now)
+            && self.watchdog_at.is_some_and(|at| at <= now);
 
-        let start = Instant::now();
-        while start.elapsed() < Duration::from_millis(180) {
-            if rx.try_recv().is_err() {
-                std::thread::sleep(Duration::from_millis(10));
+        self.batch_started = None;
+        self.hold_until = None;
+        self.watchdog_at = self.watchdog.map(|interval| now + interval);
+        let result = self.controller.enforce();
+        self.recheck_at = result
+            .as_ref()
+            .ok()
+            .and_then(|r| r.recheck_after)
+            .map(|delay| now + delay);
+
+        if watchdog_check {
+            let drifted = result.as_ref().is_ok_and(|r| r.changed);
+            self.drift_streak = if drifted { self.drift_streak + 1 } else { 0 };
+            self.finding = match self.drift_streak {
+                0 => None,
+                n if n >= WATCHDOG_REINSTALL_AFTER => {
+                    self.drift_streak = 0;
+                    Some(WatchdogFinding::Reinstall)
+                }
+                _ => Some(WatchdogFinding::MissedNotification),
+            };
+            if self.finding.is_some() {
+                self.controller.metrics.missed_notification();
             }
         }
+        result
+    }
+
+    /// What the last watchdog check found, if it has not been taken yet.
+    pub fn take_watchdog_finding(&mut self) -> Option<WatchdogFinding> {
+        self.finding.take()
+    }
+}
+
+/// Whether `event` reports the lock's own switch of the default input.
+pub fn is_own_switch(controller: &Controller, event: &AudioEvent) -> bool {
+    match event {
+        AudioEvent::DefaultInputChanged {
+            current: Some(device),
+            at,
+            ..
+        } => controller.is_own_switch(device.id, *at),
+        _ => false,
+    }
+}
 
-        let _ = controller.enforce();
-        ui.request_refresh();
+/// What the enforcement worker reports to and drives, besides the
+/// controller it enforces with.
+#[cfg(target_os = "macos")]
+pub struct WorkerDeps {
+    pub controller: Arc<Controller>,
+    pub ui: UiNotifier,
+    pub watcher: Arc<DeviceWatcher>,
+    pub monitor: Option<Arc<HealthMonitor>>,
+    pub recorder: Option<Arc<TraceRecorder>>,
+    pub control: Option<Arc<ControlServer>>,
+    pub history: Arc<History>,
+    /// How often to poll for a missed notification, if at all.
+    pub watchdog: Option<Duration>,
+}
+
+#[cfg(target_os = "macos")]
+pub fn run_enforcement_worker(rx: Receiver<AudioEvent>, deps: WorkerDeps) {
+    let WorkerDeps {
+        controller,
+        ui,
+        watcher,
+        monitor,
+        recorder,
+        control,
+        history,
+        watchdog,
+    } = deps;
+    std::thread::spawn(move || {
+        let mut core = WorkerCore::new(controller.clone()).with_watchdog(watchdog, Instant::now());
+        let mut stream_changed = false;
+        let mut service_restarted = false;
+        // From the first event of a batch until it has been enforced. A batch
+        // started by a HAL notification continues that notification's trace.
+        let mut batch_span: Option<tracing::Span> = None;
+        loop {
+            let received = match core.next_deadline() {
+                Some(deadline) => match rx.recv_deadline(deadline) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match rx.recv() {
+                    Ok(event) => Some(event),
+                    Err(_) => break,
+                },
+            };
+
+            if let Some(event) = received {
+                debug!(?event, "audio event");
+                let notification = event.at().and_then(spans::take_notification);
+                match batch_span.as_ref() {
+                    Some(batch) => {
+                        if let Some(notification) = notification.as_ref() {
+                            batch.follows_from(notification);
+                        }
+                    }
+                    None => {
+                        batch_span = Some(info_span!(
+                            parent: notification.as_ref().and_then(|span| span.id()),
+                            "debounce",
+                            first_event = ?event,
+                            device_id = event.device().map(|device| device.id),
+                        ));
+                    }
+                }
+                stream_changed |= matches!(
+                    event,
+                    AudioEvent::StreamConfigChanged(_)
+                        | AudioEvent::DidWake
+                        | AudioEvent::SessionActive
+                );
+                service_restarted |= matches!(event, AudioEvent::ServiceRestarted);
+                if let Some(recorder) = recorder.as_ref() {
+                    recorder.event(&event);
+                }
+                if let Some(control) = control.as_ref() {
+                    control.publish(&event);
+                }
+                history.observe(&event, is_own_switch(&controller, &event));
+                core.on_event(&event, Instant::now());
+                continue;
+            }
+
+            let now = Instant::now();
+            if !core.is_due(now) {
+                continue;
+            }
+
+            if service_restarted {
+                // The old registrations died with the server; the listeners
+                // for the locked and running devices come back below.
+                info!("audio server restarted, reinstalling listeners");
+                if let Err(e) = watcher.reinstall() {
+                    warn!(error = ?e, "could not reinstall audio listeners");
+                }
+            }
+
+            let before = controller.snapshot();
+            if let Some(recorder) = recorder.as_ref() {
+                recorder.devices(controller.backend());
+            }
+            let span = batch_span.take().unwrap_or_else(|| info_span!("recheck"));
+            let result = span.in_scope(|| core.run(now));
+            drop(span);
+            if let Err(e) = result.as_ref() {
+                warn!(error = ?e, "enforcing the lock failed");
+            }
+            if let Some(recorder) = recorder.as_ref() {
+                recorder.decision(&before, &result, controller.backend());
+            }
+            if let Some(finding) = core.take_watchdog_finding() {
+                let reinstall = finding == WatchdogFinding::Reinstall;
+                if let Some(recorder) = recorder.as_ref() {
+                    recorder.missed_notification(reinstall);
+                }
+                if reinstall {
+                    warn!("audio notifications keep going missing, reinstalling listeners");
+                    if let Err(e) = watcher.reinstall() {
+                        warn!(error = ?e, "could not reinstall audio listeners");
+                    }
+                } else {
+                    info!("watchdog found a missed notification");
+                }
+            }
+            if let Ok(current) = audio_manager::get_default_input_device() {
+                watcher.watch_running_state(current);
+            }
+            let locked = controller.locked_input();
+            watcher.watch_locked_device(locked.clone());
+            if let Some(monitor) = monitor.as_ref() {
+                // A new stream format, or a stream that slept, needs a fresh
+                // IOProc.
+                if stream_changed || service_restarted {
+                    monitor.follow(None);
+                }
+                monitor.follow(locked);
+            }
+            stream_changed = false;
+            service_restarted = false;
+            ui.request_refresh();
+        }
     });
 }
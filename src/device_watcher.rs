@@ -1,146 +1,468 @@
 use std::ffi::c_void;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use crossbeam_channel::Sender;
+use tracing::{debug, debug_span, info, warn};
 
+use crate::audio_backend::four_cc;
+use crate::audio_manager::{self, AudioError};
 use crate::audio_sys::*;
-use crate::controller::AudioEvent;
-use crate::audio_manager::AudioError;
+use crate::controller::{AudioEvent, DeviceRef};
+use crate::spans;
 
 struct ListenerContext {
     tx: Sender<AudioEvent>,
+    last_default: Mutex<Option<DeviceRef>>,
+    last_devices: Mutex<Vec<DeviceRef>>,
+}
+
+impl ListenerContext {
+    fn default_input_changed(&self, at: Instant) {
+        let current = audio_manager::get_default_input_device()
+            .ok()
+            .and_then(device_ref);
+        let previous = {
+            let mut last = self.last_default.lock().expect("last default");
+            std::mem::replace(&mut *last, current.clone())
+        };
+        tracing::Span::current().record("device_id", current.as_ref().map(|d| d.id));
+        debug!(
+            previous_id = previous.as_ref().map(|d| d.id),
+            current_id = current.as_ref().map(|d| d.id),
+            current_uid = current.as_ref().map(|d| d.uid.as_str()),
+            "default input changed"
+        );
+        let _ = self.tx.send(AudioEvent::DefaultInputChanged {
+            previous,
+            current,
+            at,
+        });
+    }
+
+    fn devices_changed(&self, at: Instant) {
+        let _ = self.tx.send(AudioEvent::DevicesChanged { at });
+
+        let current = input_device_refs();
+        let previous = {
+            let mut last = self.last_devices.lock().expect("last devices");
+            std::mem::replace(&mut *last, current.clone())
+        };
+
+        for device in previous
+            .iter()
+            .filter(|d| !current.iter().any(|c| c.uid == d.uid))
+        {
+            info!(device_id = device.id, uid = %device.uid, name = %device.name, "input device removed");
+            let _ = self.tx.send(AudioEvent::DeviceRemoved {
+                device: device.clone(),
+                at,
+            });
+        }
+        for device in current
+            .iter()
+            .filter(|d| !previous.iter().any(|p| p.uid == d.uid))
+        {
+            info!(device_id = device.id, uid = %device.uid, name = %device.name, "input device added");
+            let _ = self.tx.send(AudioEvent::DeviceAdded {
+                device: device.clone(),
+                at,
+            });
+        }
+    }
+}
+
+fn device_ref(id: AudioDeviceID) -> Option<DeviceRef> {
+    let uid = audio_manager::device_uid_by_id(id).ok()?;
+    let name = audio_manager::device_name_by_id(id).unwrap_or_else(|_| uid.clone());
+    Some(DeviceRef { id, uid, name })
+}
+
+fn input_device_refs() -> Vec<DeviceRef> {
+    audio_manager::list_input_devices()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|d| DeviceRef {
+            id: d.id,
+            uid: d.uid,
+            name: d.name,
+        })
+        .collect()
 }
 
 unsafe extern "C" fn audio_object_listener(
-    _in_object_id: AudioObjectID,
+    in_object_id: AudioObjectID,
     in_num_addresses: u32,
     in_addresses: *const AudioObjectPropertyAddress,
     in_client_data: *mut c_void,
 ) -> OSStatus {
     let ctx = &*(in_client_data as *const ListenerContext);
+    let at = Instant::now();
+    let span = debug_span!(
+        "hal_notification",
+        object_id = in_object_id,
+        selectors = tracing::field::Empty,
+        device_id = tracing::field::Empty,
+    );
+    let _entered = span.enter();
     if in_addresses.is_null() || in_num_addresses == 0 {
-        let _ = ctx.tx.send(AudioEvent::DefaultInputChanged);
+        ctx.default_input_changed(at);
+        spans::notification_sent(at, &span);
         return 0;
     }
 
     let addresses = std::slice::from_raw_parts(in_addresses, in_num_addresses as usize);
+    span.record(
+        "selectors",
+        addresses
+            .iter()
+            .map(|addr| four_cc(addr.mSelector))
+            .collect::<Vec<_>>()
+            .join(",")
+            .as_str(),
+    );
+    // Only events that carry `at` can lead the worker back to this span.
+    let mut timed = false;
     for addr in addresses {
         match addr.mSelector {
             K_AUDIO_HARDWARE_PROPERTY_DEFAULT_INPUT_DEVICE => {
-                let _ = ctx.tx.send(AudioEvent::DefaultInputChanged);
+                ctx.default_input_changed(at);
+                timed = true;
             }
             K_AUDIO_HARDWARE_PROPERTY_DEVICES => {
-                let _ = ctx.tx.send(AudioEvent::DevicesChanged);
+                ctx.devices_changed(at);
+                timed = true;
             }
             K_AUDIO_HARDWARE_PROPERTY_SERVICE_RESTARTED => {
+                warn!("audio server restarted");
                 let _ = ctx.tx.send(AudioEvent::ServiceRestarted);
             }
+            K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_RUNNING_SOMEWHERE => {
+                let _ = ctx.tx.send(AudioEvent::InputRunningChanged);
+            }
             _ => {}
         }
     }
+    if timed {
+        spans::notification_sent(at, &span);
+    }
 
     0
 }
 
+struct DeviceContext {
+    uid: String,
+    tx: Sender<AudioEvent>,
+}
+
+unsafe extern "C" fn device_object_listener(
+    in_object_id: AudioObjectID,
+    in_num_addresses: u32,
+    in_addresses: *const AudioObjectPropertyAddress,
+    in_client_data: *mut c_void,
+) -> OSStatus {
+    let ctx = &*(in_client_data as *const DeviceContext);
+    if in_addresses.is_null() || in_num_addresses == 0 {
+        return 0;
+    }
+
+    let addresses = std::slice::from_raw_parts(in_addresses, in_num_addresses as usize);
+    for addr in addresses {
+        match addr.mSelector {
+            K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_ALIVE
+                if !audio_manager::device_is_alive(in_object_id).unwrap_or(false) =>
+            {
+                info!(device_id = in_object_id, uid = %ctx.uid, "locked device died");
+                let _ = ctx.tx.send(AudioEvent::DeviceDied(ctx.uid.clone()));
+            }
+            K_AUDIO_DEVICE_PROPERTY_STREAM_CONFIGURATION
+            | K_AUDIO_DEVICE_PROPERTY_NOMINAL_SAMPLE_RATE => {
+                debug!(device_id = in_object_id, uid = %ctx.uid, "stream format changed");
+                let _ = ctx
+                    .tx
+                    .send(AudioEvent::StreamConfigChanged(ctx.uid.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    0
+}
+
+/// Properties watched on the locked device itself.
+const DEVICE_PROPERTIES: [(AudioObjectPropertySelector, AudioObjectPropertyScope); 3] = [
+    (
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_ALIVE,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+    ),
+    (
+        K_AUDIO_DEVICE_PROPERTY_STREAM_CONFIGURATION,
+        K_AUDIO_DEVICE_PROPERTY_SCOPE_INPUT,
+    ),
+    (
+        K_AUDIO_DEVICE_PROPERTY_NOMINAL_SAMPLE_RATE,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+    ),
+];
+
+fn device_property_address(
+    selector: AudioObjectPropertySelector,
+    scope: AudioObjectPropertyScope,
+) -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: scope,
+        mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+    }
+}
+
+/// Listeners registered on one device object, removed again on drop.
+struct DeviceListener {
+    device_id: AudioDeviceID,
+    ctx_raw: *mut DeviceContext,
+}
+
+impl DeviceListener {
+    fn add(
+        device_id: AudioDeviceID,
+        uid: String,
+        tx: Sender<AudioEvent>,
+    ) -> Result<Self, AudioError> {
+        unsafe {
+            let ctx_raw = Box::into_raw(Box::new(DeviceContext { uid, tx }));
+            let listener = Self { device_id, ctx_raw };
+
+            for (selector, scope) in DEVICE_PROPERTIES {
+                let status = AudioObjectAddPropertyListener(
+                    device_id,
+                    &device_property_address(selector, scope),
+                    Some(device_object_listener),
+                    ctx_raw.cast::<c_void>(),
+                );
+                if status != 0 {
+                    return Err(AudioError::OsStatus(status));
+                }
+            }
+
+            Ok(listener)
+        }
+    }
+}
+
+impl Drop for DeviceListener {
+    fn drop(&mut self) {
+        unsafe {
+            for (selector, scope) in DEVICE_PROPERTIES {
+                let _ = AudioObjectRemovePropertyListener(
+                    self.device_id,
+                    &device_property_address(selector, scope),
+                    Some(device_object_listener),
+                    self.ctx_raw.cast::<c_void>(),
+                );
+            }
+            let _ = Box::from_raw(self.ctx_raw);
+        }
+    }
+}
+
+fn running_somewhere_address() -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_RUNNING_SOMEWHERE,
+        mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+    }
+}
+
 pub struct DeviceWatcher {
     ctx_raw: *mut ListenerContext,
+    running_device: Mutex<Option<AudioDeviceID>>,
+    locked_device: Mutex<Option<DeviceListener>>,
+}
+
+// The contexts are only read from HAL callbacks; listener changes go through
+// `running_device` and `locked_device`.
+unsafe impl Send for DeviceWatcher {}
+unsafe impl Sync for DeviceWatcher {}
+
+/// Properties watched on the system object.
+const SYSTEM_PROPERTIES: [AudioObjectPropertySelector; 3] = [
+    K_AUDIO_HARDWARE_PROPERTY_DEFAULT_INPUT_DEVICE,
+    K_AUDIO_HARDWARE_PROPERTY_DEVICES,
+    K_AUDIO_HARDWARE_PROPERTY_SERVICE_RESTARTED,
+];
+
+fn system_property_address(selector: AudioObjectPropertySelector) -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+    }
+}
+
+/// Registers the system object listeners. The restart notification is best
+/// effort; the other two are required.
+unsafe fn add_system_listeners(ctx_raw: *mut ListenerContext) -> Result<(), AudioError> {
+    for selector in SYSTEM_PROPERTIES {
+        let status = AudioObjectAddPropertyListener(
+            K_AUDIO_OBJECT_SYSTEM_OBJECT,
+            &system_property_address(selector),
+            Some(audio_object_listener),
+            ctx_raw.cast::<c_void>(),
+        );
+        if status != 0 && selector != K_AUDIO_HARDWARE_PROPERTY_SERVICE_RESTARTED {
+            return Err(AudioError::OsStatus(status));
+        }
+    }
+    Ok(())
+}
+
+unsafe fn remove_system_listeners(ctx_raw: *mut ListenerContext) {
+    for selector in SYSTEM_PROPERTIES {
+        let _ = AudioObjectRemovePropertyListener(
+            K_AUDIO_OBJECT_SYSTEM_OBJECT,
+            &system_property_address(selector),
+            Some(audio_object_listener),
+            ctx_raw.cast::<c_void>(),
+        );
+    }
 }
 
 impl DeviceWatcher {
     pub fn start(tx: Sender<AudioEvent>) -> Result<Self, AudioError> {
         unsafe {
-            let ctx = Box::new(ListenerContext { tx });
+            let ctx = Box::new(ListenerContext {
+                tx,
+                last_default: Mutex::new(
+                    audio_manager::get_default_input_device()
+                        .ok()
+                        .and_then(device_ref),
+                ),
+                last_devices: Mutex::new(input_device_refs()),
+            });
             let ctx_raw = Box::into_raw(ctx);
 
-            let default_addr = AudioObjectPropertyAddress {
-                mSelector: K_AUDIO_HARDWARE_PROPERTY_DEFAULT_INPUT_DEVICE,
-                mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
-                mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
-            };
-            let devices_addr = AudioObjectPropertyAddress {
-                mSelector: K_AUDIO_HARDWARE_PROPERTY_DEVICES,
-                mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
-                mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+            if let Err(e) = add_system_listeners(ctx_raw) {
+                remove_system_listeners(ctx_raw);
+                let _ = Box::from_raw(ctx_raw);
+                return Err(e);
+            }
+
+            let watcher = Self {
+                ctx_raw,
+                running_device: Mutex::new(None),
+                locked_device: Mutex::new(None),
             };
-            let service_addr = AudioObjectPropertyAddress {
-                mSelector: K_AUDIO_HARDWARE_PROPERTY_SERVICE_RESTARTED,
-                mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
-                mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+            if let Ok(current) = audio_manager::get_default_input_device() {
+                watcher.watch_running_state(current);
+            }
+
+            Ok(watcher)
+        }
+    }
+
+    /// Tears down every listener and installs them again, for when the audio
+    /// server has restarted and the old registrations and device IDs are
+    /// gone. The per-device listeners come back on the next
+    /// `watch_locked_device` / `watch_running_state`.
+    pub fn reinstall(&self) -> Result<(), AudioError> {
+        self.locked_device.lock().expect("locked device").take();
+        self.unwatch_running_state();
+
+        unsafe {
+            remove_system_listeners(self.ctx_raw);
+
+            // Devices now have new IDs; diffing against the old ones would
+            // report every device as removed and added again.
+            let ctx = &*self.ctx_raw;
+            *ctx.last_default.lock().expect("last default") =
+                audio_manager::get_default_input_device()
+                    .ok()
+                    .and_then(device_ref);
+            *ctx.last_devices.lock().expect("last devices") = input_device_refs();
+
+            add_system_listeners(self.ctx_raw)?;
+        }
+
+        if let Ok(current) = audio_manager::get_default_input_device() {
+            self.watch_running_state(current);
+        }
+        Ok(())
+    }
+
+    /// Moves the device-level listeners to the current lock target, or drops
+    /// them when there is none.
+    pub fn watch_locked_device(&self, target: Option<(AudioDeviceID, String)>) {
+        let mut watched = self.locked_device.lock().expect("locked device");
+        if watched.as_ref().map(|l| l.device_id) == target.as_ref().map(|(id, _)| *id) {
+            return;
+        }
+
+        watched.take();
+        if let Some((device_id, uid)) = target {
+            let tx = unsafe { (*self.ctx_raw).tx.clone() };
+            *watched = match DeviceListener::add(device_id, uid.clone(), tx) {
+                Ok(listener) => Some(listener),
+                Err(e) => {
+                    warn!(device_id, uid = %uid, error = ?e, "could not watch the locked device");
+                    None
+                }
             };
+        }
+    }
 
-            let status = AudioObjectAddPropertyListener(
-                K_AUDIO_OBJECT_SYSTEM_OBJECT,
-                &default_addr,
-                Some(audio_object_listener),
-                ctx_raw.cast::<c_void>(),
-            );
-            if status != 0 {
-                return Err(AudioError::OsStatus(status));
-            }
+    /// Moves the "running somewhere" listener to `device_id`, so a deferred
+    /// switch can be applied as soon as the current input goes idle.
+    pub fn watch_running_state(&self, device_id: AudioDeviceID) {
+        if *self.running_device.lock().expect("running device") == Some(device_id) {
+            return;
+        }
+        self.unwatch_running_state();
 
+        let mut watched = self.running_device.lock().expect("running device");
+        unsafe {
+            let address = running_somewhere_address();
             let status = AudioObjectAddPropertyListener(
-                K_AUDIO_OBJECT_SYSTEM_OBJECT,
-                &devices_addr,
+                device_id,
+                &address,
                 Some(audio_object_listener),
-                ctx_raw.cast::<c_void>(),
+                self.ctx_raw.cast::<c_void>(),
             );
-            if status != 0 {
-                return Err(AudioError::OsStatus(status));
+            if status == 0 {
+                *watched = Some(device_id);
+            } else {
+                warn!(
+                    device_id,
+                    status, "could not watch whether the default input is running"
+                );
             }
+        }
+    }
 
-            let _ = AudioObjectAddPropertyListener(
-                K_AUDIO_OBJECT_SYSTEM_OBJECT,
-                &service_addr,
-                Some(audio_object_listener),
-                ctx_raw.cast::<c_void>(),
-            );
-
-            Ok(Self { ctx_raw })
+    fn unwatch_running_state(&self) {
+        if let Some(device_id) = self.running_device.lock().expect("running device").take() {
+            unsafe {
+                let _ = AudioObjectRemovePropertyListener(
+                    device_id,
+                    &running_somewhere_address(),
+                    Some(audio_object_listener),
+                    self.ctx_raw.cast::<c_void>(),
+                );
+            }
         }
     }
 }
 
 impl Drop for DeviceWatcher {
     fn drop(&mut self) {
-        unsafe {
-            if self.ctx_raw.is_null() {
-                return;
-            }
+        if self.ctx_raw.is_null() {
+            return;
+        }
 
-            let default_addr = AudioObjectPropertyAddress {
-                mSelector: K_AUDIO_HARDWARE_PROPERTY_DEFAULT_INPUT_DEVICE,
-                mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
-                mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
-            };
-            let devices_addr = AudioObjectPropertyAddress {
-                mSelector: K_AUDIO_HARDWARE_PROPERTY_DEVICES,
-                mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
-                mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
-            };
-            let service_addr = AudioObjectPropertyAddress {
-                mSelector: K_AUDIO_HARDWARE_PROPERTY_SERVICE_RESTARTED,
-                mScope: K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
-                mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
-            };
-
-            let _ = AudioObjectRemovePropertyListener(
-                K_AUDIO_OBJECT_SYSTEM_OBJECT,
-                &default_addr,
-                Some(audio_object_listener),
-                self.ctx_raw.cast::<c_void>(),
-            );
-            let _ = AudioObjectRemovePropertyListener(
-                K_AUDIO_OBJECT_SYSTEM_OBJECT,
-                &devices_addr,
-                Some(audio_object_listener),
-                self.ctx_raw.cast::<c_void>(),
-            );
-            let _ = AudioObjectRemovePropertyListener(
-                K_AUDIO_OBJECT_SYSTEM_OBJECT,
-                &service_addr,
-                Some(audio_object_listener),
-                self.ctx_raw.cast::<c_void>(),
-            );
+        self.locked_device.lock().expect("locked device").take();
+        self.unwatch_running_state();
 
+        unsafe {
+            remove_system_listeners(self.ctx_raw);
             let _ = Box::from_raw(self.ctx_raw);
         }
     }
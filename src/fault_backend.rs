@@ -0,0 +1,150 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio_backend::{
+    AudioBackend, AudioDeviceID, AudioError, AudioProcess, DeviceInfo, Operation,
+};
+
+/// A failure to inject into one backend operation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fault {
+    pub op: Operation,
+    /// Fail with this OSStatus.
+    #[serde(default)]
+    pub status: Option<i32>,
+    /// Fail with `AudioError::NotFound`. Ignored when `status` is set.
+    #[serde(default)]
+    pub not_found: bool,
+    /// Delay before the call runs (or fails).
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Calls to let through untouched before the fault applies.
+    #[serde(default)]
+    pub skip: u32,
+    /// How many calls the fault applies to. Unlimited when absent.
+    #[serde(default)]
+    pub times: Option<u32>,
+}
+
+impl Fault {
+    fn error(&self) -> Option<AudioError> {
+        match self.status {
+            Some(status) => Some(AudioError::OsStatus(status)),
+            None if self.not_found => Some(AudioError::NotFound),
+            None => None,
+        }
+    }
+}
+
+type Sleep = Box<dyn Fn(Duration) + Send + Sync>;
+
+/// Wraps a backend and injects errors and latency into chosen operations,
+/// to exercise the agent's error paths.
+pub struct FaultyBackend {
+    inner: Arc<dyn AudioBackend>,
+    faults: Mutex<Vec<Fault>>,
+    sleep: Sleep,
+}
+
+impl FaultyBackend {
+    pub fn new(inner: Arc<dyn AudioBackend>) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(Vec::new()),
+            sleep: Box::new(std::thread::sleep),
+        }
+    }
+
+    /// Replaces how latency is spent, e.g. with a virtual clock.
+    pub fn with_sleep(mut self, sleep: impl Fn(Duration) + Send + Sync + 'static) -> Self {
+        self.sleep = Box::new(sleep);
+        self
+    }
+
+    pub fn inject(&self, fault: Fault) {
+        self.faults.lock().expect("faults").push(fault);
+    }
+
+    pub fn clear(&self) {
+        self.faults.lock().expect("faults").clear();
+    }
+
+    /// Applies the first active fault for `op`. Faults that have run out are
+    /// dropped.
+    fn check(&self, op: Operation) -> Result<(), AudioError> {
+        let (latency, error) = {
+            let mut faults = self.faults.lock().expect("faults");
+            let Some(index) = faults.iter().position(|f| f.op == op) else {
+                return Ok(());
+            };
+
+            let fault = &mut faults[index];
+            if fault.skip > 0 {
+                fault.skip -= 1;
+                return Ok(());
+            }
+            let applied = (Duration::from_millis(fault.latency_ms), fault.error());
+            if let Some(times) = fault.times.as_mut() {
+                *times = times.saturating_sub(1);
+                if *times == 0 {
+                    faults.remove(index);
+                }
+            }
+            applied
+        };
+
+        if !latency.is_zero() {
+            (self.sleep)(latency);
+        }
+        error.map_or(Ok(()), Err)
+    }
+}
+
+impl AudioBackend for FaultyBackend {
+    fn list_input_devices(&self) -> Result<Vec<DeviceInfo>, AudioError> {
+        self.check(Operation::ListInputDevices)?;
+        self.inner.list_input_devices()
+    }
+
+    fn get_default_input_device(&self) -> Result<AudioDeviceID, AudioError> {
+        self.check(Operation::GetDefaultInputDevice)?;
+        self.inner.get_default_input_device()
+    }
+
+    fn set_default_input_device(&self, device_id: AudioDeviceID) -> Result<(), AudioError> {
+        self.check(Operation::SetDefaultInputDevice)?;
+        self.inner.set_default_input_device(device_id)
+    }
+
+    fn device_name_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError> {
+        self.check(Operation::DeviceNameById)?;
+        self.inner.device_name_by_id(device_id)
+    }
+
+    fn device_uid_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError> {
+        self.check(Operation::DeviceUidById)?;
+        self.inner.device_uid_by_id(device_id)
+    }
+
+    fn device_id_for_uid(&self, uid: &str) -> Result<AudioDeviceID, AudioError> {
+        self.check(Operation::DeviceIdForUid)?;
+        self.inner.device_id_for_uid(uid)
+    }
+
+    fn device_is_alive(&self, device_id: AudioDeviceID) -> Result<bool, AudioError> {
+        self.check(Operation::DeviceIsAlive)?;
+        self.inner.device_is_alive(device_id)
+    }
+
+    fn device_is_running_somewhere(&self, device_id: AudioDeviceID) -> Result<bool, AudioError> {
+        self.check(Operation::DeviceIsRunningSomewhere)?;
+        self.inner.device_is_running_somewhere(device_id)
+    }
+
+    fn list_processes(&self) -> Result<Vec<AudioProcess>, AudioError> {
+        self.check(Operation::ListProcesses)?;
+        self.inner.list_processes()
+    }
+}
@@ -0,0 +1,157 @@
+use std::ffi::c_void;
+use std::mem;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+
+use crate::audio_manager::{self, AudioError};
+use crate::audio_sys::*;
+use crate::controller::AudioEvent;
+use crate::silence::{HealthChange, SilenceDetector};
+
+struct StreamContext {
+    uid: String,
+    detector: SilenceDetector,
+    tx: Sender<AudioEvent>,
+}
+
+unsafe extern "C" fn input_io_proc(
+    _in_device: AudioObjectID,
+    _in_now: *const c_void,
+    in_input_data: *const AudioBufferList,
+    _in_input_time: *const c_void,
+    _out_output_data: *mut AudioBufferList,
+    _in_output_time: *const c_void,
+    in_client_data: *mut c_void,
+) -> OSStatus {
+    if in_input_data.is_null() || in_client_data.is_null() {
+        return 0;
+    }
+
+    // Only the device's IO thread touches the context while the proc runs.
+    let ctx = &mut *(in_client_data as *mut StreamContext);
+    let abl = &*in_input_data;
+    let buffers = std::slice::from_raw_parts(abl.mBuffers.as_ptr(), abl.mNumberBuffers as usize);
+
+    // IOProcs see Float32 samples. The first stream is enough to tell a dead
+    // input from a live one, and feeding several would double-count time.
+    let Some(buffer) = buffers.iter().find(|b| !b.mData.is_null()) else {
+        return 0;
+    };
+    let count = buffer.mDataByteSize as usize / mem::size_of::<f32>();
+    let samples = std::slice::from_raw_parts(buffer.mData as *const f32, count);
+
+    match ctx
+        .detector
+        .process(samples, buffer.mNumberChannels as usize)
+    {
+        Some(HealthChange::Silent) => {
+            let _ = ctx.tx.send(AudioEvent::InputSilent(ctx.uid.clone()));
+        }
+        Some(HealthChange::Recovered) => {
+            let _ = ctx.tx.send(AudioEvent::InputRecovered(ctx.uid.clone()));
+        }
+        None => {}
+    }
+
+    0
+}
+
+struct ActiveStream {
+    device_id: AudioDeviceID,
+    proc_id: AudioDeviceIOProcID,
+    ctx_raw: *mut StreamContext,
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = AudioDeviceStop(self.device_id, self.proc_id);
+            let _ = AudioDeviceDestroyIOProcID(self.device_id, self.proc_id);
+            let _ = Box::from_raw(self.ctx_raw);
+        }
+    }
+}
+
+/// Opens a lightweight input stream on the locked device and reports
+/// `InputSilent` / `InputRecovered` events when its level dies or returns.
+pub struct HealthMonitor {
+    tx: Sender<AudioEvent>,
+    threshold_dbfs: f32,
+    silence_limit: Duration,
+    active: Mutex<Option<ActiveStream>>,
+}
+
+// The stream context is owned by the IO thread while running; `active` is
+// only touched under its mutex.
+unsafe impl Send for HealthMonitor {}
+unsafe impl Sync for HealthMonitor {}
+
+impl HealthMonitor {
+    pub fn new(tx: Sender<AudioEvent>, threshold_dbfs: f32, silence_limit: Duration) -> Self {
+        Self {
+            tx,
+            threshold_dbfs,
+            silence_limit,
+            active: Mutex::new(None),
+        }
+    }
+
+    /// Moves the stream to `target`, or closes it when there is nothing to
+    /// monitor.
+    pub fn follow(&self, target: Option<(AudioDeviceID, String)>) {
+        let mut active = self.active.lock().expect("health stream");
+        if active.as_ref().map(|a| a.device_id) == target.as_ref().map(|(id, _)| *id) {
+            return;
+        }
+
+        active.take();
+        if let Some((device_id, uid)) = target {
+            // On failure nothing is active, so the next call tries again.
+            match self.start(device_id, uid.clone()) {
+                Ok(stream) => *active = Some(stream),
+                Err(e) => {
+                    tracing::warn!(device_id, uid = %uid, error = ?e, "could not start the silence monitor")
+                }
+            }
+        }
+    }
+
+    fn start(&self, device_id: AudioDeviceID, uid: String) -> Result<ActiveStream, AudioError> {
+        let sample_rate = audio_manager::device_sample_rate(device_id).unwrap_or(48_000.0);
+        let ctx = Box::new(StreamContext {
+            uid,
+            detector: SilenceDetector::new(sample_rate, self.threshold_dbfs, self.silence_limit),
+            tx: self.tx.clone(),
+        });
+        let ctx_raw = Box::into_raw(ctx);
+
+        let mut proc_id: AudioDeviceIOProcID = None;
+        unsafe {
+            let status = AudioDeviceCreateIOProcID(
+                device_id,
+                Some(input_io_proc),
+                ctx_raw.cast::<c_void>(),
+                &mut proc_id,
+            );
+            if status != 0 {
+                let _ = Box::from_raw(ctx_raw);
+                return Err(AudioError::OsStatus(status));
+            }
+
+            let status = AudioDeviceStart(device_id, proc_id);
+            if status != 0 {
+                let _ = AudioDeviceDestroyIOProcID(device_id, proc_id);
+                let _ = Box::from_raw(ctx_raw);
+                return Err(AudioError::OsStatus(status));
+            }
+        }
+
+        Ok(ActiveStream {
+            device_id,
+            proc_id,
+            ctx_raw,
+        })
+    }
+}
@@ -0,0 +1,631 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::controller::{AudioEvent, DeviceRef};
+
+/// Entries kept in the history file; older ones are dropped.
+pub const HISTORY_CAPACITY: usize = 500;
+/// Lines the file may grow to before it is compacted to the newest
+/// `HISTORY_CAPACITY` entries. Changes in between are appended.
+const COMPACT_AT: usize = 2 * HISTORY_CAPACITY;
+
+/// A device that showed up this long before it became the default input
+/// counts as just added.
+const JUST_ADDED: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangedBy {
+    /// macOS or another app.
+    System,
+    Soundstoic,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryDevice {
+    pub uid: String,
+    pub name: String,
+}
+
+/// One change of the default input. A change Soundstoic reverted is kept as
+/// a single entry with `reverted` set rather than as two changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Milliseconds since the Unix epoch.
+    pub at_ms: u64,
+    pub from: Option<HistoryDevice>,
+    pub to: Option<HistoryDevice>,
+    pub by: ChangedBy,
+    /// `to` was connected just before it became the default.
+    pub device_added: bool,
+    pub reverted: bool,
+    /// How long after the change the revert happened.
+    pub revert_ms: Option<u64>,
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |device: &Option<HistoryDevice>| {
+            device
+                .as_ref()
+                .map_or("none".to_string(), |d| d.name.clone())
+        };
+        write!(f, "{} -> {}", name(&self.from), name(&self.to))?;
+        match self.by {
+            ChangedBy::System => write!(f, " by the system")?,
+            ChangedBy::Soundstoic => write!(f, " by Soundstoic")?,
+        }
+        if self.device_added {
+            write!(f, ", just connected")?;
+        }
+        if let Some(ms) = self.revert_ms {
+            write!(f, ", reverted after {} ms", ms)?;
+        } else if self.reverted {
+            write!(f, ", reverted")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct HistoryState {
+    entries: VecDeque<HistoryEntry>,
+    /// When recently connected devices showed up, by UID.
+    added: HashMap<String, Instant>,
+    /// Set while the newest entry is a system change Soundstoic has not
+    /// reverted yet: when it happened.
+    open: Option<Instant>,
+    /// Lines in the file.
+    file_lines: usize,
+    /// Where the newest line in the file starts.
+    last_line: u64,
+}
+
+/// A ring buffer of default input changes, mirrored to a JSON-lines file
+/// that each change is appended to.
+pub struct History {
+    path: Option<PathBuf>,
+    state: Mutex<HistoryState>,
+}
+
+pub fn default_path() -> PathBuf {
+    let base = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
+    base.join("soundstoic").join("history.jsonl")
+}
+
+impl History {
+    /// Continues the history in `path`, starting empty if it cannot be read.
+    /// The file is compacted once, which also drops a torn last line that
+    /// the next change would otherwise be appended to.
+    pub fn open(path: PathBuf) -> Self {
+        let mut state = HistoryState {
+            entries: load(&path).unwrap_or_default().into(),
+            ..HistoryState::default()
+        };
+        compact(&path, &mut state);
+        Self {
+            path: Some(path),
+            state: Mutex::new(state),
+        }
+    }
+
+    /// A history that is not saved anywhere.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            state: Mutex::new(HistoryState::default()),
+        }
+    }
+
+    /// Records what `event` says about the default input. `by_us` tells
+    /// whether a default input change was Soundstoic's own switch.
+    pub fn observe(&self, event: &AudioEvent, by_us: bool) {
+        let mut state = self.state.lock().expect("history");
+        match event {
+            AudioEvent::DeviceAdded { device, at } => {
+                state
+                    .added
+                    .retain(|_, added| at.saturating_duration_since(*added) < JUST_ADDED);
+                state.added.insert(device.uid.clone(), *at);
+            }
+            AudioEvent::DefaultInputChanged {
+                previous,
+                current,
+                at,
+            } => {
+                if by_us {
+                    if let Some(opened) = state.open.take() {
+                        let entry = state.entries.back_mut().expect("open entry");
+                        // Switching away from the device the system picked.
+                        if same_device(&entry.to, previous) {
+                            entry.reverted = true;
+                            entry.revert_ms =
+                                Some(at.saturating_duration_since(opened).as_millis() as u64);
+                            let entry = entry.clone();
+                            if let Some(path) = self.path.as_ref() {
+                                replace_last(path, &mut state, &entry);
+                            }
+                            return;
+                        }
+                    }
+                }
+
+                let device_added = current.as_ref().is_some_and(|d| {
+                    state
+                        .added
+                        .get(&d.uid)
+                        .is_some_and(|added| at.saturating_duration_since(*added) < JUST_ADDED)
+                });
+                let entry = HistoryEntry {
+                    at_ms: wall_ms(*at),
+                    from: previous.as_ref().map(history_device),
+                    to: current.as_ref().map(history_device),
+                    by: if by_us {
+                        ChangedBy::Soundstoic
+                    } else {
+                        ChangedBy::System
+                    },
+                    device_added,
+                    reverted: false,
+                    revert_ms: None,
+                };
+                state.entries.push_back(entry.clone());
+                state.open = (!by_us).then_some(*at);
+                while state.entries.len() > HISTORY_CAPACITY {
+                    state.entries.pop_front();
+                }
+                if let Some(path) = self.path.as_ref() {
+                    if state.file_lines >= COMPACT_AT {
+                        compact(path, &mut state);
+                    } else {
+                        append(path, &mut state, &entry);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The most recent `count` entries, newest first.
+    pub fn recent(&self, count: usize) -> Vec<HistoryEntry> {
+        let state = self.state.lock().expect("history");
+        state.entries.iter().rev().take(count).cloned().collect()
+    }
+}
+
+/// Rewrites the file with the entries in memory.
+fn compact(path: &Path, state: &mut HistoryState) {
+    let entries: Vec<HistoryEntry> = state.entries.iter().cloned().collect();
+    match write(path, &entries) {
+        Ok(last_line) => {
+            state.file_lines = entries.len();
+            state.last_line = last_line;
+        }
+        Err(e) => tracing::warn!(path = %path.display(), error = %e, "could not save history"),
+    }
+}
+
+fn append(path: &Path, state: &mut HistoryState, entry: &HistoryEntry) {
+    match append_line(path, entry) {
+        Ok(start) => {
+            state.file_lines += 1;
+            state.last_line = start;
+        }
+        Err(e) => tracing::warn!(path = %path.display(), error = %e, "could not save history"),
+    }
+}
+
+/// Replaces the newest line, for an entry that has just been reverted.
+fn replace_last(path: &Path, state: &mut HistoryState, entry: &HistoryEntry) {
+    let replaced = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(state.last_line))
+        .and_then(|()| append_line(path, entry));
+    if let Err(e) = replaced {
+        tracing::warn!(path = %path.display(), error = %e, "could not save history");
+    }
+}
+
+/// Appends `entry` as one line and returns where the line starts.
+fn append_line(path: &Path, entry: &HistoryEntry) -> io::Result<u64> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let start = file.metadata()?.len();
+    file.write_all(&line)?;
+    Ok(start)
+}
+
+fn same_device(a: &Option<HistoryDevice>, b: &Option<DeviceRef>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.uid == b.uid,
+        _ => false,
+    }
+}
+
+fn history_device(device: &DeviceRef) -> HistoryDevice {
+    HistoryDevice {
+        uid: device.uid.clone(),
+        name: device.name.clone(),
+    }
+}
+
+/// `at` as milliseconds since the Unix epoch.
+fn wall_ms(at: Instant) -> u64 {
+    let now = Instant::now();
+    match at.checked_duration_since(now) {
+        Some(ahead) => now_ms() + ahead.as_millis() as u64,
+        None => now_ms().saturating_sub(now.duration_since(at).as_millis() as u64),
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn load(path: &Path) -> io::Result<Vec<HistoryEntry>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries: VecDeque<HistoryEntry> = VecDeque::new();
+    for line in reader.lines() {
+        // A torn last line from a crash is skipped, not fatal.
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push_back(entry);
+        }
+        if entries.len() > HISTORY_CAPACITY {
+            entries.pop_front();
+        }
+    }
+    Ok(entries.into())
+}
+
+/// Replaces the file in one rename, so readers never see half of it.
+/// Returns where the last line starts.
+fn write(path: &Path, entries: &[HistoryEntry]) -> io::Result<u64> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let partial = path.with_extension("jsonl.partial");
+    let mut out = io::BufWriter::new(File::create(&partial)?);
+    let mut len = 0;
+    let mut last_line = 0;
+    for entry in entries {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        out.write_all(&line)?;
+        last_line = len;
+        len += line.len() as u64;
+    }
+    out.flush()?;
+    drop(out);
+    fs::rename(&partial, path)?;
+    Ok(last_line)
+}
+
+/// `at_ms` as local `YYYY-MM-DD HH:MM:SS`.
+pub fn local_time(at_ms: u64) -> String {
+    let secs = (at_ms / 1000) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return format!("@{}", secs);
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// `soundstoic history [--json] [-n COUNT]`.
+pub fn print(args: &[String]) -> Result<(), String> {
+    let mut json = false;
+    let mut count = 50;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-n" => {
+                count = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("-n needs a number")?;
+            }
+            other => return Err(format!("unknown option {}", other)),
+        }
+    }
+
+    let path = default_path();
+    let entries = match load(&path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(format!("could not read {}: {}", path.display(), e)),
+    };
+    let recent = &entries[entries.len().saturating_sub(count)..];
+
+    if json {
+        let out = serde_json::to_string_pretty(recent).map_err(|e| e.to_string())?;
+        println!("{}", out);
+        return Ok(());
+    }
+    if recent.is_empty() {
+        println!("no default input changes recorded yet");
+    }
+    for entry in recent {
+        println!("{}  {}", local_time(entry.at_ms), entry);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(id: u32, uid: &str) -> DeviceRef {
+        DeviceRef {
+            id,
+            uid: uid.to_string(),
+            name: uid.to_uppercase(),
+        }
+    }
+
+    fn changed(previous: &DeviceRef, current: &DeviceRef, at: Instant) -> AudioEvent {
+        AudioEvent::DefaultInputChanged {
+            previous: Some(previous.clone()),
+            current: Some(current.clone()),
+            at,
+        }
+    }
+
+    #[test]
+    fn a_revert_of_a_system_change_is_folded_into_it() {
+        let (mic, airpods) = (device(1, "mic"), device(2, "airpods"));
+        let start = Instant::now();
+        let history = History::in_memory();
+
+        history.observe(&changed(&mic, &airpods, start), false);
+        history.observe(
+            &changed(&airpods, &mic, start + Duration::from_millis(300)),
+            true,
+        );
+
+        let entries = history.recent(10);
+        assert_eq!(entries.len(), 1);
+        let entry = &entries[0];
+        assert_eq!(entry.by, ChangedBy::System);
+        assert_eq!(entry.to.as_ref().unwrap().uid, "airpods");
+        assert!(entry.reverted);
+        assert_eq!(entry.revert_ms, Some(300));
+    }
+
+    #[test]
+    fn a_switch_away_from_another_device_is_its_own_entry() {
+        let (mic, airpods, usb) = (device(1, "mic"), device(2, "airpods"), device(3, "usb"));
+        let start = Instant::now();
+        let history = History::in_memory();
+
+        history.observe(&changed(&mic, &airpods, start), false);
+        history.observe(
+            &changed(&usb, &mic, start + Duration::from_millis(100)),
+            true,
+        );
+
+        let entries = history.recent(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].by, ChangedBy::Soundstoic);
+        assert!(!entries[0].reverted);
+        assert!(!entries[1].reverted);
+        assert_eq!(entries[1].revert_ms, None);
+    }
+
+    #[test]
+    fn only_the_newest_system_change_can_be_reverted() {
+        let (mic, airpods) = (device(1, "mic"), device(2, "airpods"));
+        let start = Instant::now();
+        let history = History::in_memory();
+
+        history.observe(&changed(&mic, &airpods, start), false);
+        history.observe(
+            &changed(&airpods, &mic, start + Duration::from_millis(100)),
+            true,
+        );
+        // Nothing is open any more, so a second switch back is not a revert.
+        history.observe(
+            &changed(&airpods, &mic, start + Duration::from_millis(200)),
+            true,
+        );
+
+        let entries = history.recent(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].by, ChangedBy::Soundstoic);
+        assert!(entries[1].reverted);
+    }
+
+    #[test]
+    fn a_device_counts_as_just_added_within_the_window() {
+        let (mic, airpods, usb) = (device(1, "mic"), device(2, "airpods"), device(3, "usb"));
+        let start = Instant::now();
+        let history = History::in_memory();
+
+        history.observe(
+            &AudioEvent::DeviceAdded {
+                device: airpods.clone(),
+                at: start,
+            },
+            false,
+        );
+        history.observe(
+            &AudioEvent::DeviceAdded {
+                device: usb.clone(),
+                at: start,
+            },
+            false,
+        );
+        history.observe(&changed(&mic, &airpods, start + JUST_ADDED / 2), false);
+        history.observe(&changed(&airpods, &usb, start + JUST_ADDED), false);
+
+        let entries = history.recent(10);
+        assert!(!entries[0].device_added, "usb showed up too long ago");
+        assert!(entries[1].device_added);
+    }
+
+    #[test]
+    fn the_oldest_entries_are_dropped_past_capacity() {
+        let start = Instant::now();
+        let history = History::in_memory();
+        let devices: Vec<DeviceRef> = (0..=HISTORY_CAPACITY as u32 + 10)
+            .map(|n| device(n, &format!("device-{}", n)))
+            .collect();
+
+        for (n, pair) in devices.windows(2).enumerate() {
+            history.observe(
+                &changed(&pair[0], &pair[1], start + Duration::from_secs(n as u64)),
+                false,
+            );
+        }
+
+        let entries = history.recent(usize::MAX);
+        assert_eq!(entries.len(), HISTORY_CAPACITY);
+        let last = devices.len() - 1;
+        assert_eq!(
+            entries[0].to.as_ref().unwrap().uid,
+            format!("device-{}", last)
+        );
+        assert_eq!(
+            entries[HISTORY_CAPACITY - 1].to.as_ref().unwrap().uid,
+            format!("device-{}", last - HISTORY_CAPACITY + 1)
+        );
+    }
+
+    #[test]
+    fn load_skips_a_torn_last_line() {
+        let dir = std::env::temp_dir().join(format!("soundstoic-history-{}", std::process::id()));
+        let path = dir.join("history.jsonl");
+        let entry = |uid: &str| HistoryEntry {
+            at_ms: 1,
+            from: None,
+            to: Some(HistoryDevice {
+                uid: uid.to_string(),
+                name: uid.to_string(),
+            }),
+            by: ChangedBy::System,
+            device_added: false,
+            reverted: false,
+            revert_ms: None,
+        };
+        write(&path, &[entry("a"), entry("b")]).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"at_ms":2,"from":null,"to":{"uid":"c""#)
+            .unwrap();
+        drop(file);
+
+        let loaded = load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let uids: Vec<&str> = loaded
+            .iter()
+            .map(|e| e.to.as_ref().unwrap().uid.as_str())
+            .collect();
+        assert_eq!(uids, ["a", "b"]);
+    }
+
+    fn temp_history(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "soundstoic-history-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("history.jsonl")
+    }
+
+    fn lines(path: &Path) -> usize {
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn changes_are_appended_and_a_revert_replaces_its_line() {
+        let (mic, airpods) = (device(1, "mic"), device(2, "airpods"));
+        let path = temp_history("append");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "{\"at_ms\":1,\"from\":null,\"to\":{\"uid\":\"torn").unwrap();
+        let start = Instant::now();
+
+        let history = History::open(path.clone());
+        history.observe(&changed(&mic, &airpods, start), false);
+        assert_eq!(lines(&path), 1);
+        history.observe(
+            &changed(&airpods, &mic, start + Duration::from_millis(250)),
+            true,
+        );
+        assert_eq!(lines(&path), 1);
+        history.observe(
+            &changed(&mic, &airpods, start + Duration::from_secs(1)),
+            false,
+        );
+
+        let loaded = load(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].revert_ms, Some(250));
+        assert!(!loaded[1].reverted);
+    }
+
+    #[test]
+    fn the_file_is_compacted_once_it_doubles() {
+        let start = Instant::now();
+        let path = temp_history("compact");
+        let history = History::open(path.clone());
+        let devices: Vec<DeviceRef> = (0..=COMPACT_AT as u32 + 1)
+            .map(|n| device(n, &format!("device-{}", n)))
+            .collect();
+
+        for (n, pair) in devices.windows(2).enumerate() {
+            history.observe(
+                &changed(&pair[0], &pair[1], start + Duration::from_secs(n as u64)),
+                false,
+            );
+            if n + 1 == COMPACT_AT {
+                assert_eq!(lines(&path), COMPACT_AT);
+            }
+        }
+
+        assert_eq!(lines(&path), HISTORY_CAPACITY);
+        let loaded = load(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        let newest = loaded.last().unwrap().to.as_ref().unwrap();
+        assert_eq!(newest.uid, format!("device-{}", COMPACT_AT + 1));
+    }
+
+    #[test]
+    fn entries_are_dated_by_their_event() {
+        let (mic, airpods) = (device(1, "mic"), device(2, "airpods"));
+        let history = History::in_memory();
+
+        history.observe(
+            &changed(&mic, &airpods, Instant::now() - Duration::from_secs(60)),
+            false,
+        );
+
+        let age_ms = now_ms() - history.recent(1)[0].at_ms;
+        assert!((59_000..61_000).contains(&age_ms), "{} ms old", age_ms);
+    }
+}
@@ -0,0 +1,244 @@
+use std::io::{self, Read};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Receiver};
+
+use crate::config::{Config, ConfigStore};
+use crate::controller::{LockEvent, LockEventKind};
+
+/// How often a running hook is checked for having exited.
+const POLL: Duration = Duration::from_millis(50);
+/// Output kept from each of a hook's stdout and stderr.
+const MAX_OUTPUT: usize = 16 * 1024;
+/// How long to wait for a hook's output once it has exited, in case
+/// something it started in the background still holds the pipes.
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Runs the `hooks` commands from the config for `events`, one at a time in
+/// the order the events happened, on a thread of their own so a slow script
+/// never holds up the lock. The config is read for each event.
+pub fn start(events: Receiver<LockEvent>, config: Arc<ConfigStore>) {
+    warn_unknown_kinds(&config.get());
+    std::thread::spawn(move || {
+        for event in events {
+            let cfg = config.get();
+            if let Some(command) = cfg.hooks.get(event.kind.as_str()) {
+                let timeout = Duration::from_secs(cfg.hook_timeout_secs);
+                log(event.kind.as_str(), timeout, run(command, &event, timeout));
+            }
+        }
+    });
+}
+
+fn warn_unknown_kinds(cfg: &Config) {
+    for kind in cfg.hooks.keys() {
+        if !LockEventKind::ALL
+            .iter()
+            .any(|known| known.as_str() == kind)
+        {
+            let known: Vec<&str> = LockEventKind::ALL.iter().map(|k| k.as_str()).collect();
+            tracing::warn!(kind, known = ?known, "hook for an unknown event, it will never run");
+        }
+    }
+}
+
+/// How a hook run ended.
+#[derive(Debug)]
+struct Outcome {
+    /// `None` if the hook ran past its timeout and was killed.
+    status: Option<ExitStatus>,
+    duration: Duration,
+    stdout: String,
+    stderr: String,
+}
+
+/// Runs `command` with `sh -c`, killing it (and anything it started) after
+/// `timeout`.
+fn run(command: &str, event: &LockEvent, timeout: Duration) -> io::Result<Outcome> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("SOUNDSTOIC_EVENT", event.kind.as_str())
+        .env("SOUNDSTOIC_DEVICE_UID", event.uid.as_deref().unwrap_or(""))
+        .env(
+            "SOUNDSTOIC_DEVICE_NAME",
+            event.name.as_deref().unwrap_or(""),
+        )
+        .env(
+            "SOUNDSTOIC_LOCK_ENABLED",
+            if event.enabled { "1" } else { "0" },
+        )
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Its own process group, so a timeout kills its children too.
+        .process_group(0)
+        .spawn()?;
+
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+    let started = Instant::now();
+    let status = wait(&mut child, timeout);
+    let duration = started.elapsed();
+    Ok(Outcome {
+        status,
+        duration,
+        stdout: stdout.recv_timeout(OUTPUT_GRACE).unwrap_or_default(),
+        stderr: stderr.recv_timeout(OUTPUT_GRACE).unwrap_or_default(),
+    })
+}
+
+/// Logs how the hook for `kind` ended, with its output. The command itself
+/// is left out: it can carry a token, and the log goes into support bundles.
+fn log(kind: &str, timeout: Duration, outcome: io::Result<Outcome>) {
+    let Outcome {
+        status,
+        duration,
+        stdout,
+        stderr,
+    } = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            tracing::warn!(event = kind, error = %e, "could not start the hook");
+            return;
+        }
+    };
+    let duration_ms = duration.as_millis() as u64;
+    match status {
+        Some(status) if status.success() => {
+            tracing::info!(event = kind, duration_ms, stdout, stderr, "hook finished");
+        }
+        Some(status) => {
+            tracing::warn!(event = kind, duration_ms, %status, stdout, stderr, "hook failed");
+        }
+        None => {
+            tracing::warn!(
+                event = kind,
+                timeout_secs = timeout.as_secs(),
+                stdout,
+                stderr,
+                "hook timed out and was killed"
+            );
+        }
+    }
+}
+
+/// The exit status, or `None` if the hook ran past `timeout` and was killed.
+fn wait(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(POLL),
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!(error = %e, "could not wait for the hook");
+                break;
+            }
+        }
+    }
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    let _ = child.wait();
+    None
+}
+
+/// Reads `pipe` to the end on another thread, keeping the first
+/// `MAX_OUTPUT` bytes.
+fn capture(pipe: Option<impl Read + Send + 'static>) -> Receiver<String> {
+    let (tx, rx) = bounded(1);
+    if let Some(mut pipe) = pipe {
+        std::thread::spawn(move || {
+            let mut kept = Vec::new();
+            let mut buf = [0u8; 4096];
+            while let Ok(n) = pipe.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                let room = MAX_OUTPUT.saturating_sub(kept.len());
+                kept.extend_from_slice(&buf[..n.min(room)]);
+            }
+            let text = String::from_utf8_lossy(&kept).trim_end().to_string();
+            let _ = tx.send(text);
+        });
+    }
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> LockEvent {
+        LockEvent {
+            kind: LockEventKind::LockRestored,
+            uid: Some("BuiltInMicrophoneDevice".to_string()),
+            name: Some("MacBook Pro Microphone".to_string()),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn the_event_is_passed_in_the_environment() {
+        let outcome = run(
+            r#"printf '%s|%s|%s|%s' "$SOUNDSTOIC_EVENT" "$SOUNDSTOIC_DEVICE_UID" "$SOUNDSTOIC_DEVICE_NAME" "$SOUNDSTOIC_LOCK_ENABLED""#,
+            &event(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(outcome.status.unwrap().success());
+        assert_eq!(
+            outcome.stdout,
+            "lock_restored|BuiltInMicrophoneDevice|MacBook Pro Microphone|1"
+        );
+    }
+
+    #[test]
+    fn output_is_capped() {
+        let outcome = run(
+            "head -c 100000 /dev/zero | tr '\\0' x; echo oops >&2; exit 3",
+            &event(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(outcome.status.unwrap().code(), Some(3));
+        assert_eq!(outcome.stdout.len(), MAX_OUTPUT);
+        assert_eq!(outcome.stderr, "oops");
+    }
+
+    #[test]
+    fn a_hook_past_its_timeout_is_killed_with_its_children() {
+        let outcome = run(
+            "sleep 30 & echo $!; wait",
+            &event(),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+        assert!(outcome.status.is_none());
+        assert!(outcome.duration < Duration::from_secs(5));
+
+        // The background sleep held the pipes; the output only arrives
+        // because it was killed too.
+        let pid: libc::pid_t = outcome.stdout.parse().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while alive(pid) {
+            assert!(
+                Instant::now() < deadline,
+                "the hook's child {} survived",
+                pid
+            );
+            std::thread::sleep(POLL);
+        }
+    }
+
+    /// Running, as opposed to gone or a zombie waiting to be reaped.
+    fn alive(pid: libc::pid_t) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.contains(") Z "),
+            // No procfs on macOS.
+            Err(_) => unsafe { libc::kill(pid, 0) == 0 },
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::{ffi::c_void, mem, ptr};
+
+use core_foundation::base::TCFType;
+use core_foundation::string::{CFString, CFStringRef};
+use serde_json::{json, Map, Value};
+
+use crate::audio_backend::{four_cc, os_status_text};
+use crate::audio_manager::{self, AudioError};
+use crate::audio_sys::*;
+
+/// How a property's data is read and shown.
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Number,
+    /// A four-character code, such as a transport type or data source.
+    Code,
+    Flag,
+    Rate,
+    RateRanges,
+    /// `AudioBufferList` with the channels of each buffer.
+    BufferList,
+    /// Stream objects, inspected in turn.
+    Streams,
+    DataSources,
+    Format,
+}
+
+struct Property {
+    key: &'static str,
+    selector: AudioObjectPropertySelector,
+    kind: Kind,
+}
+
+const fn property(
+    key: &'static str,
+    selector: AudioObjectPropertySelector,
+    kind: Kind,
+) -> Property {
+    Property {
+        key,
+        selector,
+        kind,
+    }
+}
+
+/// Device properties in the global scope.
+const DEVICE_PROPERTIES: &[Property] = &[
+    property("name", K_AUDIO_OBJECT_PROPERTY_NAME, Kind::Text),
+    property("model_name", K_AUDIO_OBJECT_PROPERTY_MODEL_NAME, Kind::Text),
+    property(
+        "manufacturer",
+        K_AUDIO_OBJECT_PROPERTY_MANUFACTURER,
+        Kind::Text,
+    ),
+    property("uid", K_AUDIO_DEVICE_PROPERTY_DEVICE_UID, Kind::Text),
+    property("model_uid", K_AUDIO_DEVICE_PROPERTY_MODEL_UID, Kind::Text),
+    property(
+        "transport_type",
+        K_AUDIO_DEVICE_PROPERTY_TRANSPORT_TYPE,
+        Kind::Code,
+    ),
+    property(
+        "clock_domain",
+        K_AUDIO_DEVICE_PROPERTY_CLOCK_DOMAIN,
+        Kind::Number,
+    ),
+    property(
+        "configuration_application",
+        K_AUDIO_DEVICE_PROPERTY_CONFIGURATION_APPLICATION,
+        Kind::Text,
+    ),
+    property(
+        "is_alive",
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_ALIVE,
+        Kind::Flag,
+    ),
+    property("is_hidden", K_AUDIO_DEVICE_PROPERTY_IS_HIDDEN, Kind::Flag),
+    property(
+        "is_running",
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_RUNNING,
+        Kind::Flag,
+    ),
+    property(
+        "is_running_somewhere",
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_IS_RUNNING_SOMEWHERE,
+        Kind::Flag,
+    ),
+    property(
+        "nominal_sample_rate",
+        K_AUDIO_DEVICE_PROPERTY_NOMINAL_SAMPLE_RATE,
+        Kind::Rate,
+    ),
+    property(
+        "available_nominal_sample_rates",
+        K_AUDIO_DEVICE_PROPERTY_AVAILABLE_NOMINAL_SAMPLE_RATES,
+        Kind::RateRanges,
+    ),
+    property(
+        "buffer_frame_size",
+        K_AUDIO_DEVICE_PROPERTY_BUFFER_FRAME_SIZE,
+        Kind::Number,
+    ),
+];
+
+/// Device properties read once for the input and once for the output scope.
+const SCOPED_PROPERTIES: &[Property] = &[
+    property(
+        "stream_configuration",
+        K_AUDIO_DEVICE_PROPERTY_STREAM_CONFIGURATION,
+        Kind::BufferList,
+    ),
+    property("streams", K_AUDIO_DEVICE_PROPERTY_STREAMS, Kind::Streams),
+    property("latency", K_AUDIO_DEVICE_PROPERTY_LATENCY, Kind::Number),
+    property(
+        "safety_offset",
+        K_AUDIO_DEVICE_PROPERTY_SAFETY_OFFSET,
+        Kind::Number,
+    ),
+    property(
+        "can_be_default",
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_CAN_BE_DEFAULT_DEVICE,
+        Kind::Flag,
+    ),
+    property(
+        "can_be_default_system",
+        K_AUDIO_DEVICE_PROPERTY_DEVICE_CAN_BE_DEFAULT_SYSTEM_DEVICE,
+        Kind::Flag,
+    ),
+    property(
+        "data_source",
+        K_AUDIO_DEVICE_PROPERTY_DATA_SOURCE,
+        Kind::Code,
+    ),
+    property(
+        "data_sources",
+        K_AUDIO_DEVICE_PROPERTY_DATA_SOURCES,
+        Kind::DataSources,
+    ),
+];
+
+const STREAM_PROPERTIES: &[Property] = &[
+    property("is_active", K_AUDIO_STREAM_PROPERTY_IS_ACTIVE, Kind::Flag),
+    property("direction", K_AUDIO_STREAM_PROPERTY_DIRECTION, Kind::Number),
+    property(
+        "terminal_type",
+        K_AUDIO_STREAM_PROPERTY_TERMINAL_TYPE,
+        Kind::Code,
+    ),
+    property(
+        "starting_channel",
+        K_AUDIO_STREAM_PROPERTY_STARTING_CHANNEL,
+        Kind::Number,
+    ),
+    property(
+        "virtual_format",
+        K_AUDIO_STREAM_PROPERTY_VIRTUAL_FORMAT,
+        Kind::Format,
+    ),
+    property(
+        "physical_format",
+        K_AUDIO_STREAM_PROPERTY_PHYSICAL_FORMAT,
+        Kind::Format,
+    ),
+];
+
+/// Every known property of every device, or of the device with `uid`, as
+/// JSON for a bug report.
+pub fn dump(uid: Option<&str>) -> Result<Value, String> {
+    let ids = match uid {
+        Some(uid) => vec![audio_manager::device_id_for_uid(uid)
+            .map_err(|e| format!("no device {}: {}", uid, error_text(&e)))?],
+        None => audio_manager::get_device_ids()
+            .map_err(|e| format!("could not list devices: {}", error_text(&e)))?,
+    };
+
+    let default = |selector| {
+        audio_manager::get_u32_property(
+            K_AUDIO_OBJECT_SYSTEM_OBJECT,
+            selector,
+            K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        )
+        .ok()
+    };
+    Ok(json!({
+        "soundstoic_version": env!("CARGO_PKG_VERSION"),
+        "default_input": default(K_AUDIO_HARDWARE_PROPERTY_DEFAULT_INPUT_DEVICE),
+        "default_output": default(K_AUDIO_HARDWARE_PROPERTY_DEFAULT_OUTPUT_DEVICE),
+        "devices": ids.into_iter().map(inspect_device).collect::<Vec<_>>(),
+    }))
+}
+
+/// `soundstoic inspect [uid]`.
+pub fn print(args: &[String]) -> Result<(), String> {
+    let uid = match args {
+        [] => None,
+        [uid] => Some(uid.as_str()),
+        _ => return Err("usage: soundstoic inspect [uid]".to_string()),
+    };
+    let dump = dump(uid)?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&dump).map_err(|e| e.to_string())?
+    );
+    Ok(())
+}
+
+fn inspect_device(id: AudioDeviceID) -> Value {
+    let mut device = Map::new();
+    device.insert("id".to_string(), json!(id));
+    inspect_into(
+        &mut device,
+        id,
+        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+        DEVICE_PROPERTIES,
+    );
+    for (key, scope) in [
+        ("input", K_AUDIO_DEVICE_PROPERTY_SCOPE_INPUT),
+        ("output", K_AUDIO_DEVICE_PROPERTY_SCOPE_OUTPUT),
+    ] {
+        let mut scoped = Map::new();
+        inspect_into(&mut scoped, id, scope, SCOPED_PROPERTIES);
+        device.insert(key.to_string(), Value::Object(scoped));
+    }
+    Value::Object(device)
+}
+
+/// Adds each of `properties` the object has to `out`, as its value or its
+/// error, with its selector and whether it can be set.
+fn inspect_into(
+    out: &mut Map<String, Value>,
+    object: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+    properties: &[Property],
+) {
+    for property in properties {
+        let address = AudioObjectPropertyAddress {
+            mSelector: property.selector,
+            mScope: scope,
+            mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+        };
+        if unsafe { AudioObjectHasProperty(object, &address) } == 0 {
+            continue;
+        }
+
+        let mut entry = Map::new();
+        entry.insert("selector".to_string(), json!(four_cc(property.selector)));
+        match read(object, &address, property.kind) {
+            Ok(value) => entry.insert("value".to_string(), value),
+            Err(e) => entry.insert("error".to_string(), json!(error_text(&e))),
+        };
+        let mut settable = 0u8;
+        let status = unsafe { AudioObjectIsPropertySettable(object, &address, &mut settable) };
+        let settable = if status == 0 {
+            json!(settable != 0)
+        } else {
+            json!(os_status_text(status))
+        };
+        entry.insert("settable".to_string(), settable);
+        out.insert(property.key.to_string(), Value::Object(entry));
+    }
+}
+
+fn read(
+    object: AudioObjectID,
+    address: &AudioObjectPropertyAddress,
+    kind: Kind,
+) -> Result<Value, AudioError> {
+    let (selector, scope) = (address.mSelector, address.mScope);
+    Ok(match kind {
+        Kind::Text => json!(audio_manager::get_cfstring_property(
+            object, selector, scope
+        )?),
+        Kind::Number => json!(audio_manager::get_u32_property(object, selector, scope)?),
+        Kind::Code => json!(four_cc(audio_manager::get_u32_property(
+            object, selector, scope
+        )?)),
+        Kind::Flag => json!(audio_manager::get_u32_property(object, selector, scope)? != 0),
+        Kind::Rate => json!(audio_manager::get_f64_property(object, selector, scope)?),
+        Kind::RateRanges => {
+            let bytes = read_bytes(object, address)?;
+            let ranges: Vec<Value> = bytes
+                .chunks_exact(mem::size_of::<AudioValueRange>())
+                .map(|range| json!([f64_at(range, 0), f64_at(range, 8)]))
+                .collect();
+            json!(ranges)
+        }
+        Kind::BufferList => {
+            // mNumberBuffers, padded to the alignment of the AudioBuffers
+            // that follow.
+            let bytes = read_bytes(object, address)?;
+            let count = u32_at(&bytes, 0).unwrap_or(0) as usize;
+            let buffers: Vec<Value> = (0..count)
+                .filter_map(|i| u32_at(&bytes, 8 + i * mem::size_of::<AudioBuffer>()))
+                .map(|channels| json!({ "channels": channels }))
+                .collect();
+            json!(buffers)
+        }
+        Kind::Streams => {
+            let streams: Vec<Value> = u32_list(&read_bytes(object, address)?)
+                .into_iter()
+                .map(|stream| {
+                    let mut out = Map::new();
+                    out.insert("id".to_string(), json!(stream));
+                    inspect_into(
+                        &mut out,
+                        stream,
+                        K_AUDIO_OBJECT_PROPERTY_SCOPE_GLOBAL,
+                        STREAM_PROPERTIES,
+                    );
+                    Value::Object(out)
+                })
+                .collect();
+            json!(streams)
+        }
+        Kind::DataSources => {
+            let sources: Vec<Value> = u32_list(&read_bytes(object, address)?)
+                .into_iter()
+                .map(|source| {
+                    json!({
+                        "id": four_cc(source),
+                        "name": data_source_name(object, address.mScope, source).ok(),
+                    })
+                })
+                .collect();
+            json!(sources)
+        }
+        Kind::Format => {
+            let bytes = read_bytes(object, address)?;
+            if bytes.len() < mem::size_of::<AudioStreamBasicDescription>() {
+                return Err(AudioError::NotFound);
+            }
+            let field = |offset| u32_at(&bytes, offset).unwrap_or(0);
+            json!({
+                "sample_rate": f64_at(&bytes, 0),
+                "format_id": four_cc(field(8)),
+                "format_flags": field(12),
+                "bytes_per_packet": field(16),
+                "frames_per_packet": field(20),
+                "bytes_per_frame": field(24),
+                "channels_per_frame": field(28),
+                "bits_per_channel": field(32),
+            })
+        }
+    })
+}
+
+fn read_bytes(
+    object: AudioObjectID,
+    address: &AudioObjectPropertyAddress,
+) -> Result<Vec<u8>, AudioError> {
+    unsafe {
+        let mut size: u32 = 0;
+        audio_manager::ok(AudioObjectGetPropertyDataSize(
+            object,
+            address,
+            0,
+            ptr::null(),
+            &mut size,
+        ))?;
+        let mut bytes = vec![0u8; size as usize];
+        audio_manager::ok(AudioObjectGetPropertyData(
+            object,
+            address,
+            0,
+            ptr::null(),
+            &mut size,
+            bytes.as_mut_ptr().cast::<c_void>(),
+        ))?;
+        bytes.truncate(size as usize);
+        Ok(bytes)
+    }
+}
+
+fn data_source_name(
+    device: AudioObjectID,
+    scope: AudioObjectPropertyScope,
+    source: u32,
+) -> Result<String, AudioError> {
+    unsafe {
+        let address = AudioObjectPropertyAddress {
+            mSelector: K_AUDIO_DEVICE_PROPERTY_DATA_SOURCE_NAME_FOR_ID_CFSTRING,
+            mScope: scope,
+            mElement: K_AUDIO_OBJECT_PROPERTY_ELEMENT_MAIN,
+        };
+        let mut name: CFStringRef = ptr::null();
+        let mut translation = AudioValueTranslation {
+            mInputData: (&source as *const u32).cast::<c_void>(),
+            mInputDataSize: mem::size_of::<u32>() as u32,
+            mOutputData: (&mut name as *mut CFStringRef).cast::<c_void>(),
+            mOutputDataSize: mem::size_of::<CFStringRef>() as u32,
+        };
+        let mut size = mem::size_of::<AudioValueTranslation>() as u32;
+        audio_manager::ok(AudioObjectGetPropertyData(
+            device,
+            &address,
+            0,
+            ptr::null(),
+            &mut size,
+            (&mut translation as *mut AudioValueTranslation).cast::<c_void>(),
+        ))?;
+        if name.is_null() {
+            return Err(AudioError::NotFound);
+        }
+        Ok(CFString::wrap_under_create_rule(name).to_string())
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let chunk = bytes.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(chunk.try_into().ok()?))
+}
+
+fn f64_at(bytes: &[u8], offset: usize) -> Option<f64> {
+    let chunk = bytes.get(offset..offset + 8)?;
+    Some(f64::from_ne_bytes(chunk.try_into().ok()?))
+}
+
+fn u32_list(bytes: &[u8]) -> Vec<u32> {
+    (0..bytes.len() / 4)
+        .filter_map(|i| u32_at(bytes, i * 4))
+        .collect()
+}
+
+fn error_text(e: &AudioError) -> String {
+    match e {
+        AudioError::OsStatus(status) => os_status_text(*status),
+        AudioError::NotFound => "not found".to_string(),
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+use crate::config::ConfigStore;
+use crate::spans::{self, BoxedLayer};
+
+/// Overrides `log_level` from the config, e.g. `SOUNDSTOIC_LOG=debug`.
+const LEVEL_ENV: &str = "SOUNDSTOIC_LOG";
+/// Set to anything but `0` to also log to stderr.
+const STDERR_ENV: &str = "SOUNDSTOIC_LOG_STDERR";
+
+/// The log file is rotated once it would grow past this.
+const MAX_LOG_BYTES: u64 = 5 * 1024 * 1024;
+/// Rotated files kept as `soundstoic.log.1` (newest) to `.3`.
+const ROTATED_FILES: usize = 3;
+
+pub fn log_path() -> PathBuf {
+    let base = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
+    base.join("soundstoic").join("logs").join("soundstoic.log")
+}
+
+/// Sends `tracing` output to a size-rotated JSON-lines file and, if asked
+/// for, to stderr in a readable form, and exports spans if the config sets
+/// that up. Only the menu bar agent logs; the subcommands print to the
+/// terminal directly.
+pub fn init(config: &ConfigStore) {
+    let cfg = config.get();
+    let level = std::env::var(LEVEL_ENV)
+        .ok()
+        .or_else(|| cfg.log_level.clone())
+        .unwrap_or_else(|| "info".to_string());
+    if let Err(e) = EnvFilter::try_new(&level) {
+        eprintln!("soundstoic: bad log level {:?} ({}), using info", level, e);
+    }
+    // Each output filters by level on its own, so span export is not cut
+    // down to what is logged.
+    let filter = || EnvFilter::try_new(&level).unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    match RotatingFile::open(log_path()) {
+        Ok(file) => layers.push(
+            fmt::layer()
+                .json()
+                .with_current_span(false)
+                .with_writer(Mutex::new(file))
+                .with_filter(filter())
+                .boxed(),
+        ),
+        Err(e) => eprintln!("soundstoic: could not open the log file: {}", e),
+    }
+
+    let to_stderr = cfg.log_stderr || std::env::var(STDERR_ENV).is_ok_and(|v| v != "0");
+    if to_stderr {
+        layers.push(
+            fmt::layer()
+                .with_writer(io::stderr)
+                .with_ansi(io::stderr().is_terminal())
+                .with_filter(filter())
+                .boxed(),
+        );
+    }
+
+    let mut problems = Vec::new();
+    layers.extend(spans::layer(&cfg, &mut problems));
+
+    let _ = tracing_subscriber::registry().with(layers).try_init();
+
+    if let Some(e) = config.load_error() {
+        tracing::warn!(path = %config.path().display(), error = e, "ignoring the config file, using defaults; changes are not saved until it is fixed");
+    }
+    for problem in problems {
+        tracing::warn!("span export: {}", problem);
+    }
+}
+
+/// Appends to `path` and shifts it to `path.1`, `path.2`, ... when it gets
+/// too big.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..ROTATED_FILES).rev() {
+            let from = rotated(&self.path, index);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, index + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > MAX_LOG_BYTES {
+            // Keep logging to the full file rather than losing lines.
+            let _ = self.rotate();
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
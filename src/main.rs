@@ -1,43 +1,163 @@
+// Off macOS only the platform-neutral model builds, so most of it is unused.
+#![cfg_attr(not(target_os = "macos"), allow(dead_code))]
+
+#[cfg(target_os = "macos")]
+mod agent;
+mod audio_backend;
+#[cfg(target_os = "macos")]
 mod audio_manager;
+#[cfg(target_os = "macos")]
 mod audio_sys;
+#[cfg(target_os = "macos")]
 mod autostart;
+mod cli;
+mod clock;
 mod config;
+mod control;
 mod controller;
+#[cfg(target_os = "macos")]
 mod device_watcher;
+mod fault_backend;
+#[cfg(target_os = "macos")]
+mod health_monitor;
+mod history;
+mod hooks;
+#[cfg(target_os = "macos")]
+mod inspect;
+mod logging;
+mod metrics;
+mod model_check;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod scenario;
+mod session_events;
+mod silence;
+mod sim_backend;
+mod spans;
+mod support_bundle;
+mod trace;
+#[cfg(target_os = "macos")]
 mod tray_ui;
+#[cfg(target_os = "macos")]
 mod ui_notifier;
 
-use std::sync::Arc;
-use std::time::Duration;
-
-use crossbeam_channel::unbounded;
-
-use crate::config::ConfigStore;
-use crate::controller::{run_enforcement_worker, Controller};
-use crate::device_watcher::DeviceWatcher;
+use std::path::{Path, PathBuf};
 
 fn main() {
-    let config = Arc::new(ConfigStore::load());
-    let cfg = config.get();
-
-    let controller = Arc::new(Controller::new(cfg.lock_enabled, cfg.locked_uid.clone()));
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [command, path] if command == "replay" => {
+            if let Err(e) = trace::replay(Path::new(path)) {
+                eprintln!("soundstoic: replay failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        [command, paths @ ..] if command == "scenario" && !paths.is_empty() => {
+            let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+            match scenario::run_files(&paths) {
+                Ok(true) => return,
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("soundstoic: scenario failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        [command, options @ ..] if command == "check" => {
+            match model_check::CheckOptions::parse(options) {
+                Ok(options) if model_check::check(&options) => return,
+                Ok(_) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("soundstoic: {}", e);
+                    std::process::exit(2);
+                }
+            }
+        }
+        [command, ..] if cli::COMMANDS.contains(&command.as_str()) => {
+            run_cli(&args);
+            return;
+        }
+        [command, options @ ..] if command == "history" => {
+            if let Err(e) = history::print(options) {
+                eprintln!("soundstoic: {}", e);
+                std::process::exit(2);
+            }
+            return;
+        }
+        [command, args @ ..] if command == "inspect" => {
+            run_inspect(args);
+            return;
+        }
+        [command, args @ ..] if command == "support-bundle" => {
+            if let Err(e) = support_bundle::run(args, &config::ConfigStore::load()) {
+                eprintln!("soundstoic: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        [command, args @ ..] if command == "ctl" => {
+            if let Err(e) = control::ctl(args) {
+                eprintln!("soundstoic: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        [command, option] if command == "session-events" && option == "--self-test" => {
+            match session_events::self_test() {
+                Ok(()) => return,
+                Err(e) => {
+                    eprintln!("soundstoic: session events: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => {}
+    }
 
-    let (tx, rx) = unbounded();
-    let watcher = DeviceWatcher::start(tx).expect("audio watcher");
+    #[cfg(target_os = "macos")]
+    agent::run();
 
-    let (app, ui) = tray_ui::init_app(controller.clone(), config.clone());
+    #[cfg(not(target_os = "macos"))]
+    {
+        eprintln!("soundstoic: the menu bar agent requires macOS");
+        std::process::exit(1);
+    }
+}
 
-    run_enforcement_worker(rx, controller.clone(), ui.clone());
+#[cfg(target_os = "macos")]
+fn run_cli(args: &[String]) {
+    let config = config::ConfigStore::load();
+    match cli::run(args, &audio_manager::CoreAudioBackend, &config) {
+        Ok(()) => {}
+        Err(cli::CliError::Usage(usage)) => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+        Err(cli::CliError::Failed(e)) => {
+            eprintln!("soundstoic: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let initial_controller = controller.clone();
-    let initial_ui = ui.clone();
-    std::thread::spawn(move || {
-        std::thread::sleep(Duration::from_millis(200));
-        let _ = initial_controller.enforce();
-        initial_ui.request_refresh();
-    });
+#[cfg(not(target_os = "macos"))]
+fn run_cli(_args: &[String]) {
+    eprintln!("soundstoic: device commands require macOS");
+    std::process::exit(1);
+}
 
-    app.run();
+#[cfg(target_os = "macos")]
+fn run_inspect(args: &[String]) {
+    if let Err(e) = inspect::print(args) {
+        eprintln!("soundstoic: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    drop(watcher);
+#[cfg(not(target_os = "macos"))]
+fn run_inspect(_args: &[String]) {
+    eprintln!("soundstoic: inspect requires macOS");
+    std::process::exit(1);
 }
@@ -0,0 +1,300 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audio_backend::AudioError;
+use crate::controller::{Controller, EnforceResult, LockSnapshot, SwitchOutcome};
+
+/// Upper bounds, in seconds, of the time-to-restore histogram buckets.
+const RESTORE_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// A scraper that has not sent its request within this long is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations at or below each bound in `RESTORE_BUCKETS`.
+    buckets: [u64; RESTORE_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(RESTORE_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct MetricsState {
+    hijacks_reverted: u64,
+    /// Failed enforcements and switches, by OSStatus (`not_found` for a
+    /// device that went away).
+    enforce_failures: BTreeMap<String, u64>,
+    missed_notifications: u64,
+    restore: Histogram,
+    /// When the default input was last moved by someone other than the lock,
+    /// until the lock has put it back or found nothing to undo.
+    hijacked_at: Option<Instant>,
+    /// How long after `hijacked_at` the lock switched back; counted once the
+    /// switch is verified.
+    restored_in: Option<Duration>,
+    missing_since: Option<Instant>,
+}
+
+/// Counters the controller and the worker keep for the metrics endpoint.
+/// Always collected; only served when `metrics_addr` is set.
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+impl Metrics {
+    /// The default input was moved by the system or another app.
+    pub fn default_moved(&self, at: Instant) {
+        let mut state = self.state.lock().expect("metrics");
+        // A second hijack before the restore still counts from the first.
+        state.hijacked_at.get_or_insert(at);
+    }
+
+    pub fn missed_notification(&self) {
+        self.state.lock().expect("metrics").missed_notifications += 1;
+    }
+
+    /// Records what an `enforce` pass did. `locked_missing` is whether the
+    /// lock is on and its device is gone.
+    pub fn record_enforce(
+        &self,
+        result: &Result<EnforceResult, AudioError>,
+        locked_missing: bool,
+        now: Instant,
+    ) {
+        let mut state = self.state.lock().expect("metrics");
+        if locked_missing {
+            state.missing_since.get_or_insert(now);
+        } else {
+            state.missing_since = None;
+        }
+
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                *state.enforce_failures.entry(status_label(e)).or_default() += 1;
+                return;
+            }
+        };
+
+        if result.changed {
+            if let Some(at) = state.hijacked_at {
+                state.restored_in = Some(now.saturating_duration_since(at));
+            }
+        }
+        match result.switch.as_ref() {
+            Some(SwitchOutcome::Applied) => {
+                if let Some(restored_in) = state.restored_in.take() {
+                    state.hijacked_at = None;
+                    state.hijacks_reverted += 1;
+                    state.restore.observe(restored_in.as_secs_f64());
+                }
+            }
+            Some(SwitchOutcome::RevertedBySystem) => state.restored_in = None,
+            Some(SwitchOutcome::Failed(e)) => {
+                *state.enforce_failures.entry(status_label(e)).or_default() += 1;
+            }
+            None => {}
+        }
+
+        // Nothing left to do or to wait for: the change needed no revert.
+        let settled = !result.changed
+            && result.switch.is_none()
+            && result.recheck_after.is_none()
+            && !result.deferred;
+        if settled && state.restored_in.is_none() {
+            state.hijacked_at = None;
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(
+        &self,
+        lock: &LockSnapshot,
+        input_devices: Option<usize>,
+        now: Instant,
+    ) -> String {
+        let state = self.state.lock().expect("metrics");
+        let mut out = String::new();
+
+        counter(
+            &mut out,
+            "soundstoic_hijacks_reverted_total",
+            "Default input changes by the system or other apps that the lock undid.",
+            state.hijacks_reverted,
+        );
+
+        header(
+            &mut out,
+            "soundstoic_enforce_failures_total",
+            "counter",
+            "Failed enforcements and switches, by OSStatus.",
+        );
+        for (status, count) in &state.enforce_failures {
+            let _ = writeln!(
+                out,
+                "soundstoic_enforce_failures_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        counter(
+            &mut out,
+            "soundstoic_missed_notifications_total",
+            "Watchdog checks that found the lock out of place without a notification.",
+            state.missed_notifications,
+        );
+
+        header(
+            &mut out,
+            "soundstoic_restore_seconds",
+            "histogram",
+            "Time from a default input change to the lock switching back.",
+        );
+        for (bound, count) in RESTORE_BUCKETS.iter().zip(state.restore.buckets) {
+            let _ = writeln!(
+                out,
+                "soundstoic_restore_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "soundstoic_restore_seconds_bucket{{le=\"+Inf\"}} {}",
+            state.restore.count
+        );
+        let _ = writeln!(out, "soundstoic_restore_seconds_sum {}", state.restore.sum);
+        let _ = writeln!(
+            out,
+            "soundstoic_restore_seconds_count {}",
+            state.restore.count
+        );
+
+        if let Some(count) = input_devices {
+            gauge(
+                &mut out,
+                "soundstoic_input_devices",
+                "Connected input devices.",
+                count as f64,
+            );
+        }
+        gauge(
+            &mut out,
+            "soundstoic_lock_enabled",
+            "1 while the input lock is on.",
+            if lock.enabled { 1.0 } else { 0.0 },
+        );
+        gauge(
+            &mut out,
+            "soundstoic_locked_device_present",
+            "1 while the locked device is connected.",
+            if lock.locked_uid.is_some() && !lock.locked_missing {
+                1.0
+            } else {
+                0.0
+            },
+        );
+        gauge(
+            &mut out,
+            "soundstoic_locked_device_missing_seconds",
+            "How long the locked device has been missing, 0 while it is connected.",
+            state.missing_since.map_or(0.0, |since| {
+                now.saturating_duration_since(since).as_secs_f64()
+            }),
+        );
+        out
+    }
+}
+
+fn status_label(e: &AudioError) -> String {
+    match e {
+        AudioError::OsStatus(status) => status.to_string(),
+        AudioError::NotFound => "not_found".to_string(),
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Serves `GET /metrics` over plain HTTP on `addr`, which has to be a
+/// loopback address.
+pub fn serve(addr: &str, controller: Arc<Controller>) -> io::Result<()> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", addr, e)))?;
+    if !addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a loopback address", addr),
+        ));
+    }
+
+    let listener = TcpListener::bind(addr)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            if let Err(e) = respond(stream, &controller) {
+                tracing::debug!(error = %e, "metrics request failed");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(stream: TcpStream, controller: &Controller) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed, but are read so the client sees a clean
+    // close.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", controller.metrics_text()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,321 @@
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use crate::audio_backend::AudioBackend;
+use crate::config::Config;
+use crate::scenario::{Run, Scenario, Step};
+use crate::sim_backend::SimDevice;
+
+/// Devices that random steps plug and unplug. The built-in mic is never
+/// unplugged, so there is always something to fall back to.
+const BUILTIN: &str = "builtin";
+const POOL: &[(&str, &str)] = &[
+    ("usb-mic", "USB Microphone"),
+    ("airpods", "AirPods Pro"),
+    ("webcam", "Webcam Microphone"),
+    ("dock-audio", "Dock Audio"),
+];
+/// Devices in the catalog from the start. `dock-audio` is the stranger that
+/// the new-device screening reverts away from.
+const KNOWN: &[&str] = &[BUILTIN, "usb-mic", "airpods", "webcam"];
+
+/// Long enough for the debounce, a stability window and its rechecks to play
+/// out with no further input.
+const SETTLE: Duration = Duration::from_secs(6);
+
+/// Seed when `--seed` is not given, so a plain `check` is reproducible.
+pub const DEFAULT_SEED: u64 = 1;
+
+pub struct CheckOptions {
+    pub runs: usize,
+    pub steps: usize,
+    pub seed: u64,
+}
+
+impl CheckOptions {
+    /// Parses `--runs N`, `--steps N` and `--seed N`.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self {
+            runs: 200,
+            steps: 60,
+            seed: DEFAULT_SEED,
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?
+                .parse::<u64>()
+                .map_err(|e| format!("{}: {}", flag, e))?;
+            match flag.as_str() {
+                "--runs" => options.runs = value as usize,
+                "--steps" => options.steps = value as usize,
+                "--seed" => options.seed = value,
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// xorshift64*, so a seed reproduces a run without extra dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn flip(&mut self) -> bool {
+        self.next() & 1 == 1
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            None
+        } else {
+            Some(&items[self.below(items.len() as u64) as usize])
+        }
+    }
+}
+
+fn initial_scenario(rng: &mut Rng) -> Scenario {
+    let locked = rng.pick(KNOWN).map(|uid| uid.to_string());
+    Scenario {
+        name: "model check".to_string(),
+        config: Config {
+            lock_enabled: rng.flip(),
+            locked_uid: locked,
+            known_uids: KNOWN.iter().map(|uid| uid.to_string()).collect(),
+            ..Config::default()
+        },
+        devices: vec![
+            device(BUILTIN, "MacBook Pro Microphone"),
+            device("usb-mic", "USB Microphone"),
+        ],
+        default: Some(BUILTIN.to_string()),
+        processes: Vec::new(),
+        faults: Vec::new(),
+        steps: Vec::new(),
+    }
+}
+
+fn device(uid: &str, name: &str) -> SimDevice {
+    SimDevice {
+        id: 0,
+        uid: uid.to_string(),
+        name: name.to_string(),
+        alive: true,
+        running: false,
+    }
+}
+
+fn random_step(rng: &mut Rng, run: &Run) -> Step {
+    let present: Vec<String> = run
+        .backend()
+        .list_input_devices()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|d| d.uid)
+        .collect();
+    let absent: Vec<&(&str, &str)> = POOL
+        .iter()
+        .filter(|(uid, _)| !present.iter().any(|p| p == uid))
+        .collect();
+    let removable: Vec<String> = present
+        .iter()
+        .filter(|uid| *uid != BUILTIN)
+        .cloned()
+        .collect();
+
+    let step = match rng.below(13) {
+        0 | 1 => rng
+            .pick(&absent)
+            .map(|(uid, name)| Step::Plug(device(uid, name))),
+        2 => rng.pick(&removable).cloned().map(Step::Unplug),
+        3 | 4 => rng.pick(&present).cloned().map(Step::SetDefault),
+        5 => Some(Step::Enable(rng.flip())),
+        6 => rng.pick(KNOWN).map(|uid| Step::Lock(uid.to_string())),
+        7 => rng.pick(&present).cloned().map(|uid| Step::SetRunning {
+            uid,
+            running: rng.flip(),
+        }),
+        8 => rng.pick(&present).cloned().map(|uid| Step::SetAlive {
+            uid,
+            alive: rng.flip(),
+        }),
+        9 => rng
+            .pick(KNOWN)
+            .map(|uid| Step::LockDuringEnforce(uid.to_string())),
+        10 => Some(Step::EnableDuringEnforce(rng.flip())),
+        11 => Some(Step::RestartService {
+            default: rng.pick(&present).cloned(),
+        }),
+        _ => None,
+    };
+    step.unwrap_or_else(|| Step::WaitMs(rng.below(1000)))
+}
+
+/// Invariants that hold after every step. Returns the violation, if any.
+fn check_step(run: &Run, step: &Step) -> Result<(), String> {
+    let set_calls = run.backend().take_set_calls();
+    let enabled = run.controller().snapshot().enabled;
+
+    // The worker may legitimately finish a switch it started before the
+    // lock was turned off under it.
+    if enabled || matches!(step, Step::EnableDuringEnforce(_)) {
+        return Ok(());
+    }
+
+    // While disabled, the default input is left alone.
+    if let Some((from, to)) = set_calls.first() {
+        let from_uid = from.and_then(|id| run.backend().device_uid_by_id(id).ok());
+        return Err(format!(
+            "default set from {:?} to device {} while the lock is off",
+            from_uid, to
+        ));
+    }
+    Ok(())
+}
+
+/// Invariants that hold once nothing has happened for `SETTLE`.
+fn check_settled(run: &Run) -> Result<(), String> {
+    let snapshot = run.controller().snapshot();
+    let (true, Some(locked_uid)) = (snapshot.enabled, snapshot.locked_uid) else {
+        return Ok(());
+    };
+    let backend = run.backend();
+
+    let Ok(locked_id) = backend.device_id_for_uid(&locked_uid) else {
+        if !snapshot.locked_missing {
+            return Err(format!(
+                "{} is unplugged but locked_missing is not set",
+                locked_uid
+            ));
+        }
+        return Ok(());
+    };
+    if snapshot.locked_missing {
+        return Err(format!(
+            "{} is present but locked_missing is set",
+            locked_uid
+        ));
+    }
+    // Giving up after repeated reverts is reported, not a failure to converge.
+    if snapshot.switch_gave_up {
+        return Ok(());
+    }
+
+    let alive = backend.device_is_alive(locked_id).unwrap_or(false);
+    let current = backend.get_default_input_device().ok();
+    let busy = current
+        .and_then(|id| backend.device_is_running_somewhere(id).ok())
+        .unwrap_or(false);
+    if alive && !busy && current != Some(locked_id) {
+        let current_uid = current.and_then(|id| backend.device_uid_by_id(id).ok());
+        return Err(format!(
+            "lock on {} did not converge, default is {:?}",
+            locked_uid, current_uid
+        ));
+    }
+    Ok(())
+}
+
+/// Runs one random sequence and returns the first violation together with
+/// the steps that led to it.
+fn find_violation(seed: u64, steps: usize) -> Option<(String, Scenario)> {
+    let mut rng = Rng::new(seed);
+    let mut scenario = initial_scenario(&mut rng);
+    let mut run = Run::start(&scenario);
+    run.backend().take_set_calls();
+
+    for index in 0..steps {
+        let step = random_step(&mut rng, &run);
+        scenario.steps.push(step.clone());
+        let applied = run.step(&step).and_then(|_| check_step(&run, &step));
+        if let Err(message) = applied {
+            return Some((
+                format!("step {}: {}", scenario.steps.len(), message),
+                scenario,
+            ));
+        }
+
+        let last = index + 1 == steps;
+        if last || rng.below(6) == 0 {
+            let settle = Step::WaitMs(SETTLE.as_millis() as u64);
+            scenario.steps.push(settle.clone());
+            let settled = run
+                .step(&settle)
+                .and_then(|_| check_step(&run, &settle))
+                .and_then(|_| check_settled(&run));
+            if let Err(message) = settled {
+                return Some((
+                    format!("step {} (settle): {}", scenario.steps.len(), message),
+                    scenario,
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// Runs `options.runs` random sequences against the controller and saves
+/// the first failing one as a scenario file. Returns whether all passed.
+pub fn check(options: &CheckOptions) -> bool {
+    for run in 0..options.runs {
+        let seed = options.seed.wrapping_add(run as u64);
+        if let Some((message, mut scenario)) = find_violation(seed, options.steps) {
+            scenario.name = format!("model check seed {}: {}", seed, message);
+            println!("FAIL  seed {}: {}", seed, message);
+            println!(
+                "reproduce with: soundstoic check --seed {} --runs 1 --steps {}",
+                seed, options.steps
+            );
+            let path = std::env::temp_dir().join(format!("soundstoic-check-{}.json", seed));
+            let saved = serde_json::to_string_pretty(&scenario)
+                .map_err(io::Error::other)
+                .and_then(|json| fs::write(&path, json));
+            match saved {
+                Ok(()) => println!("steps saved as a scenario: {}", path.display()),
+                Err(e) => eprintln!("soundstoic: could not save the scenario: {}", e),
+            }
+            return false;
+        }
+    }
+    println!(
+        "{} run(s) of {} step(s) passed, seeds {}..{}",
+        options.runs,
+        options.steps,
+        options.seed,
+        options.seed.wrapping_add(options.runs as u64)
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_sequences_keep_the_invariants() {
+        let options = CheckOptions {
+            runs: 200,
+            steps: 60,
+            seed: DEFAULT_SEED,
+        };
+        assert!(check(&options));
+    }
+}
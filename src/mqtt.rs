@@ -0,0 +1,413 @@
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+
+use crate::cli::{self, CliError};
+use crate::config::{ConfigStore, MqttConfig};
+use crate::control;
+use crate::controller::{AudioEvent, Controller, LockEvent, LockEventKind};
+
+/// How often the state is read for changes no lock event reports, such as a
+/// new default input while the lock is off.
+const STATE_POLL: Duration = Duration::from_secs(1);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Wait before reconnecting after the broker could not be reached.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Messages queued for the broker. Past that they are dropped; the state
+/// is published again on reconnect anyway.
+const QUEUE: usize = 64;
+/// Discovery messages list every input device.
+const MAX_PACKET: usize = 256 * 1024;
+
+/// Publishes the lock state (retained), lock events and Home Assistant
+/// discovery to the broker in `settings`, and applies commands from it
+/// through the same `Controller` and `ConfigStore` as the menu. Reconnects
+/// on its own.
+pub fn start(
+    settings: MqttConfig,
+    controller: Arc<Controller>,
+    config: Arc<ConfigStore>,
+    events: Sender<AudioEvent>,
+) {
+    let node_id = topic_level(settings.node_id.clone().unwrap_or_else(host_name));
+    let base = format!(
+        "{}/{}",
+        settings.topic_prefix.trim_end_matches('/'),
+        node_id
+    );
+
+    let mut options = MqttOptions::new(
+        format!("soundstoic-{}", node_id),
+        settings.host.clone(),
+        settings.port,
+    );
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_max_packet_size(MAX_PACKET, MAX_PACKET);
+    options.set_last_will(LastWill::new(
+        format!("{}/availability", base),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some(username) = settings.username.as_ref() {
+        options.set_credentials(username, settings.password.clone().unwrap_or_default());
+    }
+    let (client, connection) = Client::new(options, QUEUE);
+
+    tracing::info!(host = %settings.host, port = settings.port, topics = %base, "connecting to the MQTT broker");
+    let bridge = Arc::new(Bridge {
+        client,
+        controller: controller.clone(),
+        config,
+        events,
+        settings,
+        node_id,
+        base,
+        connected: AtomicBool::new(false),
+        published: Mutex::new(Published::default()),
+    });
+
+    let connected = bridge.clone();
+    std::thread::spawn(move || connected.run_connection(connection));
+    let lock_events = controller.subscribe();
+    std::thread::spawn(move || bridge.run_state(lock_events));
+}
+
+/// What was last sent, so only changes are published.
+#[derive(Default)]
+struct Published {
+    state: Option<Value>,
+    /// Device names in the discovery messages.
+    devices: Option<Vec<String>>,
+}
+
+struct Bridge {
+    client: Client,
+    controller: Arc<Controller>,
+    config: Arc<ConfigStore>,
+    events: Sender<AudioEvent>,
+    settings: MqttConfig,
+    node_id: String,
+    /// `<topic_prefix>/<node_id>`.
+    base: String,
+    /// Nothing is queued while the broker is away; it would only be sent
+    /// stale on reconnect, when everything is published again anyway.
+    connected: AtomicBool,
+    published: Mutex<Published>,
+}
+
+impl Bridge {
+    /// Drives the connection: (re)subscribes and republishes everything on
+    /// each connect, and applies incoming commands.
+    fn run_connection(&self, mut connection: Connection) {
+        let mut connected = false;
+        let mut failing = false;
+        for notification in connection.iter() {
+            match notification {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    tracing::info!("connected to the MQTT broker");
+                    connected = true;
+                    failing = false;
+                    self.connected.store(true, Ordering::SeqCst);
+                    self.on_connect();
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if publish.retain {
+                        // A retained command would be applied again on
+                        // every reconnect.
+                        tracing::debug!(topic = %publish.topic, "ignoring a retained MQTT command");
+                        continue;
+                    }
+                    let payload = String::from_utf8_lossy(&publish.payload);
+                    self.command(&publish.topic, payload.trim());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    if connected {
+                        tracing::warn!(error = %e, "lost the MQTT broker, reconnecting");
+                    } else if !failing {
+                        tracing::warn!(error = %e, "cannot reach the MQTT broker, retrying");
+                    } else {
+                        tracing::debug!(error = %e, "cannot reach the MQTT broker");
+                    }
+                    connected = false;
+                    failing = true;
+                    self.connected.store(false, Ordering::SeqCst);
+                    std::thread::sleep(RECONNECT_DELAY);
+                }
+            }
+        }
+    }
+
+    fn on_connect(&self) {
+        let commands = format!("{}/set/+", self.base);
+        if let Err(e) = self.client.try_subscribe(commands, QoS::AtLeastOnce) {
+            tracing::warn!(error = %e, "could not subscribe to MQTT commands");
+        }
+        self.publish(
+            &format!("{}/availability", self.base),
+            "online".to_string(),
+            true,
+        );
+        // The broker may have lost the retained messages.
+        *self.published.lock().expect("published") = Published::default();
+        self.publish_state();
+    }
+
+    /// Publishes lock events as they come and the state when it changes.
+    fn run_state(&self, lock_events: Receiver<LockEvent>) {
+        loop {
+            match lock_events.recv_timeout(STATE_POLL) {
+                Ok(event) => {
+                    self.publish_event(&event);
+                    self.publish_state();
+                }
+                Err(RecvTimeoutError::Timeout) => self.publish_state(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn publish(&self, topic: &str, payload: String, retain: bool) {
+        if !self.connected.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload)
+        {
+            tracing::debug!(topic, error = %e, "could not queue an MQTT message");
+        }
+    }
+
+    fn publish_event(&self, event: &LockEvent) {
+        let payload = json!({
+            "event_type": event.kind.as_str(),
+            "uid": event.uid,
+            "name": event.name,
+            "enabled": event.enabled,
+        });
+        self.publish(&format!("{}/event", self.base), payload.to_string(), false);
+    }
+
+    fn publish_state(&self) {
+        if !self.connected.load(Ordering::SeqCst) {
+            return;
+        }
+        let state = self.state();
+        let devices: Vec<String> = self
+            .controller
+            .backend()
+            .list_input_devices()
+            .map(|devices| devices.into_iter().map(|d| d.name).collect())
+            .unwrap_or_default();
+
+        let mut published = self.published.lock().expect("published");
+        if self.settings.discovery && published.devices.as_ref() != Some(&devices) {
+            self.publish_discovery(&devices);
+            published.devices = Some(devices);
+        }
+        if published.state.as_ref() != Some(&state) {
+            self.publish(&format!("{}/state", self.base), state.to_string(), true);
+            published.state = Some(state);
+        }
+    }
+
+    fn state(&self) -> Value {
+        let snapshot = self.controller.snapshot();
+        let backend = self.controller.backend();
+        let current = backend.get_default_input_device().ok();
+        let locked = snapshot
+            .locked_uid
+            .as_deref()
+            .and_then(|uid| backend.device_id_for_uid(uid).ok());
+        json!({
+            "enabled": snapshot.enabled,
+            "locked_uid": snapshot.locked_uid,
+            "locked_name": locked.and_then(|id| backend.device_name_by_id(id).ok()),
+            "locked_present": locked.is_some(),
+            "current_uid": current.and_then(|id| backend.device_uid_by_id(id).ok()),
+            "current_name": current.and_then(|id| backend.device_name_by_id(id).ok()),
+            "failed_over_to": snapshot.failed_over_to,
+            "deferred": snapshot.deferred,
+        })
+    }
+
+    /// Home Assistant entities: the lock as a switch, the locked input as a
+    /// select, the current input, whether the locked device is connected,
+    /// and lock events.
+    fn publish_discovery(&self, devices: &[String]) {
+        let node = &self.node_id;
+        let state_topic = format!("{}/state", self.base);
+        let device = json!({
+            "identifiers": [format!("soundstoic_{}", node)],
+            "name": format!("Soundstoic {}", node),
+            "manufacturer": "Soundstoic",
+            "model": "Input lock",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let entity = |object_id: &str, name: &str, fields: Value| {
+            let mut config = json!({
+                "name": name,
+                "unique_id": format!("soundstoic_{}_{}", node, object_id),
+                "availability_topic": format!("{}/availability", self.base),
+                "device": device,
+            });
+            if let (Some(config), Value::Object(fields)) = (config.as_object_mut(), fields) {
+                config.extend(fields);
+            }
+            config
+        };
+
+        let mut entities = vec![
+            (
+                "switch",
+                "lock",
+                entity(
+                    "lock",
+                    "Input lock",
+                    json!({
+                        "state_topic": state_topic,
+                        "value_template": "{{ 'ON' if value_json.enabled else 'OFF' }}",
+                        "command_topic": format!("{}/set/lock", self.base),
+                        "icon": "mdi:microphone-settings",
+                    }),
+                ),
+            ),
+            (
+                "sensor",
+                "current_input",
+                entity(
+                    "current_input",
+                    "Current input",
+                    json!({
+                        "state_topic": state_topic,
+                        "value_template": "{{ value_json.current_name }}",
+                        "icon": "mdi:microphone",
+                    }),
+                ),
+            ),
+            (
+                "binary_sensor",
+                "locked_present",
+                entity(
+                    "locked_present",
+                    "Locked input connected",
+                    json!({
+                        "state_topic": state_topic,
+                        "value_template": "{{ 'ON' if value_json.locked_present else 'OFF' }}",
+                        "device_class": "connectivity",
+                    }),
+                ),
+            ),
+            (
+                "event",
+                "lock_event",
+                entity(
+                    "lock_event",
+                    "Lock event",
+                    json!({
+                        "state_topic": format!("{}/event", self.base),
+                        "event_types": LockEventKind::ALL.map(LockEventKind::as_str),
+                    }),
+                ),
+            ),
+        ];
+        // A select needs at least one option.
+        if !devices.is_empty() {
+            entities.push((
+                "select",
+                "locked_input",
+                entity(
+                    "locked_input",
+                    "Locked input",
+                    json!({
+                        "state_topic": state_topic,
+                        "value_template": "{{ value_json.locked_name }}",
+                        "command_topic": format!("{}/set/input", self.base),
+                        "options": devices,
+                        "icon": "mdi:microphone-variant",
+                    }),
+                ),
+            ));
+        }
+
+        for (component, object_id, config) in entities {
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                self.settings.discovery_prefix, component, node, object_id
+            );
+            self.publish(&topic, config.to_string(), true);
+        }
+    }
+
+    /// `<base>/set/lock` takes `ON` or `OFF`; `<base>/set/input` takes a
+    /// device UID or name to lock to.
+    fn command(&self, topic: &str, payload: &str) {
+        let result = match topic.strip_prefix(&format!("{}/set/", self.base)) {
+            Some("lock") => match payload.to_ascii_lowercase().as_str() {
+                "on" | "true" | "1" => Ok(true),
+                "off" | "false" | "0" => Ok(false),
+                _ => Err("expected ON or OFF".to_string()),
+            }
+            .map(|enabled| control::set_enabled(&self.controller, &self.config, enabled)),
+            Some("input") => cli::find_device(self.controller.backend(), payload)
+                .map(|device| {
+                    control::set_locked_uid(&self.controller, &self.config, Some(device.uid))
+                })
+                .map_err(|e| match e {
+                    CliError::Usage(e) | CliError::Failed(e) => e,
+                }),
+            _ => Err("unknown command".to_string()),
+        };
+
+        match result {
+            Ok(()) => {
+                tracing::info!(topic, payload, "applied an MQTT command");
+                if let Err(e) = self.controller.enforce_now() {
+                    tracing::warn!(error = ?e, "enforcing the lock failed");
+                }
+                let _ = self.events.send(AudioEvent::SettingsChanged);
+                self.publish_state();
+            }
+            Err(e) => tracing::warn!(topic, payload, error = %e, "ignored an MQTT command"),
+        }
+    }
+}
+
+fn host_name() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return "soundstoic".to_string();
+    }
+    let name = CStr::from_bytes_until_nul(&buf)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    name.trim_end_matches(".local").to_string()
+}
+
+/// `name` as a topic level and Home Assistant object id: lowercase letters,
+/// digits, `_` and `-`.
+fn topic_level(name: String) -> String {
+    let level: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if level.is_empty() {
+        "soundstoic".to_string()
+    } else {
+        level
+    }
+}
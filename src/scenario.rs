@@ -0,0 +1,599 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_channel::Receiver;
+use serde::{Deserialize, Serialize};
+
+use crate::audio_backend::{self, AudioBackend, AudioError, Operation};
+use crate::clock::{Clock, VirtualClock};
+use crate::config::Config;
+use crate::controller::{
+    is_own_switch, AudioEvent, Controller, DeviceRef, EnforceResult, LockEvent, WatchdogFinding,
+    WorkerCore,
+};
+use crate::fault_backend::{Fault, FaultyBackend};
+use crate::history::{History, HISTORY_CAPACITY};
+use crate::sim_backend::{SimBackend, SimDevice, SimProcess};
+
+/// A scripted run of the lock against the simulated device model.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Scenario {
+    pub name: String,
+    pub config: Config,
+    #[serde(default)]
+    pub devices: Vec<SimDevice>,
+    /// UID of the default input when the scenario starts.
+    #[serde(default)]
+    pub default: Option<String>,
+    /// Audio client processes at the start.
+    #[serde(default)]
+    pub processes: Vec<SimProcess>,
+    /// Faults active from the start.
+    #[serde(default)]
+    pub faults: Vec<Fault>,
+    pub steps: Vec<Step>,
+}
+
+/// One scenario step. Steps that change the device model emit the same
+/// events the device watcher would; nothing is enforced until time passes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// A device is connected. `id` may be left out.
+    Plug(SimDevice),
+    Unplug(String),
+    /// The system (not Soundstoic) makes a device the default input.
+    SetDefault(String),
+    /// Like `set_default`, but the HAL notification never arrives.
+    SetDefaultUnnoticed(String),
+    SetRunning {
+        uid: String,
+        running: bool,
+    },
+    /// `alive: false` is reported as the device dying.
+    SetAlive {
+        uid: String,
+        alive: bool,
+    },
+    /// Replaces the audio client processes.
+    Processes(Vec<SimProcess>),
+    Silent(String),
+    Recovered(String),
+    /// The Mac goes to sleep; `wake` brings it back.
+    Sleep,
+    Wake,
+    /// Fast user switching to another user's session and back.
+    SessionInactive,
+    SessionActive,
+    /// The user picks a locked input in the menu.
+    Lock(String),
+    /// The user turns the lock on or off in the menu.
+    Enable(bool),
+    /// The user picks a locked input while the worker is in the middle of
+    /// enforcing the previous one.
+    LockDuringEnforce(String),
+    /// The user toggles the lock while the worker is enforcing.
+    EnableDuringEnforce(bool),
+    Approve(String),
+    /// The user allows a new device from the menu prompt, which switches to
+    /// it.
+    Allow(String),
+    Ignore(String),
+    /// coreaudiod restarts. Devices come back under new IDs, and the default
+    /// input becomes `default` if given.
+    RestartService {
+        #[serde(default)]
+        default: Option<String>,
+    },
+    /// Injects a fault into the backend the lock talks to. Latency is spent
+    /// on the virtual clock.
+    Inject(Fault),
+    ClearFaults,
+    /// The device disappears just as the lock tries to make it the default.
+    UnplugDuringSet(String),
+    WaitMs(u64),
+    ExpectDefault(String),
+    ExpectLockedMissing(bool),
+    ExpectPending(Option<String>),
+    ExpectDeferred(bool),
+    ExpectStabilizing(bool),
+    ExpectFailedOverTo(Option<String>),
+    /// The error from the most recent enforcement, as `OsStatus(-50)` or
+    /// `NotFound`, or `null` if it succeeded.
+    ExpectError(Option<String>),
+    /// The last switch outcome as the menu shows it, e.g. `applied` or
+    /// `failed (OSStatus -50)`, or `null` before any.
+    ExpectSwitch(Option<String>),
+    ExpectGaveUp(bool),
+    /// Watchdog checks so far that found the lock out of place.
+    ExpectMissedNotifications(u32),
+    /// Times so far the watchdog asked for the listeners to be reinstalled.
+    ExpectReinstalls(u32),
+    /// Every default input change recorded so far, oldest first, as
+    /// `soundstoic history` prints them without the time.
+    ExpectHistory(Vec<String>),
+    /// The value of one series on the metrics endpoint, named as it is
+    /// printed there, e.g. `soundstoic_restore_seconds_bucket{le="0.5"}`.
+    ExpectMetric {
+        series: String,
+        value: f64,
+    },
+    /// The menu's "In use by" lines, e.g. `us.zoom.xos — USB Microphone`.
+    ExpectInUseBy(Vec<String>),
+    /// The lock events since the last `expect_lock_events`, oldest first,
+    /// e.g. `device_added usb-mic` or `lock_toggled off`.
+    ExpectLockEvents(Vec<String>),
+}
+
+/// What the device watcher can see of the device model.
+#[derive(PartialEq)]
+struct Observed {
+    default: Option<DeviceRef>,
+    devices: Vec<DeviceRef>,
+}
+
+/// Drives a `WorkerCore` over a `SimBackend` on a virtual clock.
+pub struct Run {
+    clock: Arc<VirtualClock>,
+    backend: Arc<SimBackend>,
+    faults: Arc<FaultyBackend>,
+    core: WorkerCore,
+    history: History,
+    lock_events: Receiver<LockEvent>,
+    last_error: Option<String>,
+    missed_notifications: u32,
+    reinstalls: u32,
+    failures: Vec<String>,
+}
+
+impl Run {
+    /// Sets up the device model and runs the agent's startup enforcement.
+    pub fn start(scenario: &Scenario) -> Self {
+        let clock = Arc::new(VirtualClock::new());
+        let backend = Arc::new(SimBackend::new());
+        for device in &scenario.devices {
+            backend.plug(device.clone());
+        }
+        if let Some(uid) = scenario.default.as_deref() {
+            let _ = backend.system_set_default(uid);
+        }
+        backend.set_processes(scenario.processes.clone());
+
+        let latency_clock = clock.clone();
+        let faults = Arc::new(
+            FaultyBackend::new(backend.clone()).with_sleep(move |d| latency_clock.advance(d)),
+        );
+        for fault in &scenario.faults {
+            faults.inject(fault.clone());
+        }
+
+        // The agent treats every device present on first launch as known.
+        let mut config = scenario.config.clone();
+        if config.known_uids.is_empty() {
+            config.known_uids = scenario.devices.iter().map(|d| d.uid.clone()).collect();
+            config.known_uids.extend(config.locked_uid.clone());
+        }
+
+        let controller = Controller::from_config(faults.clone(), &config).with_clock(clock.clone());
+        let lock_events = controller.subscribe();
+        let watchdog = config
+            .watchdog_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        let core = WorkerCore::new(Arc::new(controller)).with_watchdog(watchdog, clock.now());
+        let mut run = Self {
+            clock,
+            backend,
+            faults,
+            core,
+            history: History::in_memory(),
+            lock_events,
+            last_error: None,
+            missed_notifications: 0,
+            reinstalls: 0,
+            failures: Vec::new(),
+        };
+        // The agent's startup pass.
+        let before = run.observe();
+        let result = run.controller().enforce();
+        run.finish_enforce(before, result);
+        run
+    }
+
+    pub fn controller(&self) -> &Controller {
+        self.core.controller()
+    }
+
+    pub fn backend(&self) -> &SimBackend {
+        &self.backend
+    }
+
+    fn observe(&self) -> Observed {
+        let devices: Vec<DeviceRef> = self
+            .backend
+            .list_input_devices()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|d| DeviceRef {
+                id: d.id,
+                uid: d.uid,
+                name: d.name,
+            })
+            .collect();
+        let default = self
+            .backend
+            .get_default_input_device()
+            .ok()
+            .and_then(|id| devices.iter().find(|d| d.id == id).cloned());
+        Observed { default, devices }
+    }
+
+    fn emit(&mut self, event: AudioEvent) {
+        let own = is_own_switch(self.controller(), &event);
+        self.history.observe(&event, own);
+        self.core.on_event(&event, self.clock.now());
+    }
+
+    /// Emits the notifications the HAL sends for what changed since `before`.
+    fn emit_changes(&mut self, before: Observed) {
+        let after = self.observe();
+        if after == before {
+            return;
+        }
+        let at = self.clock.now();
+
+        if after.devices != before.devices {
+            self.emit(AudioEvent::DevicesChanged { at });
+            for device in &before.devices {
+                if !after.devices.contains(device) {
+                    self.emit(AudioEvent::DeviceRemoved {
+                        device: device.clone(),
+                        at,
+                    });
+                }
+            }
+            for device in &after.devices {
+                if !before.devices.contains(device) {
+                    self.emit(AudioEvent::DeviceAdded {
+                        device: device.clone(),
+                        at,
+                    });
+                }
+            }
+        }
+
+        if after.default != before.default {
+            self.emit(AudioEvent::DefaultInputChanged {
+                previous: before.default,
+                current: after.default,
+                at,
+            });
+        }
+    }
+
+    fn finish_enforce(&mut self, before: Observed, result: Result<EnforceResult, AudioError>) {
+        self.last_error = result.err().map(|e| format!("{:?}", e));
+        self.emit_changes(before);
+    }
+
+    /// Enforces right away and nudges the worker, as the menu actions do.
+    fn menu_enforce(&mut self) {
+        let before = self.observe();
+        let result = self.controller().enforce();
+        self.finish_enforce(before, result);
+        let at = self.clock.now();
+        self.core.on_event(&AudioEvent::SettingsChanged, at);
+    }
+
+    /// Lets `duration` of virtual time pass, running the worker at each
+    /// deadline it reaches.
+    fn wait(&mut self, duration: Duration) {
+        let target = self.clock.now() + duration;
+        let mut last_run = None;
+        while let Some(deadline) = self.core.next_deadline() {
+            // A zero-length recheck must not stall the clock.
+            let mut at = deadline.max(self.clock.now());
+            if last_run == Some(at) {
+                at += Duration::from_millis(1);
+            }
+            if at > target {
+                break;
+            }
+            self.clock.advance_to(at);
+            last_run = Some(at);
+
+            let before = self.observe();
+            let result = self.core.run(at);
+            self.finish_enforce(before, result);
+            match self.core.take_watchdog_finding() {
+                Some(WatchdogFinding::MissedNotification) => self.missed_notifications += 1,
+                Some(WatchdogFinding::Reinstall) => {
+                    self.missed_notifications += 1;
+                    self.reinstalls += 1;
+                }
+                None => {}
+            }
+        }
+        self.clock.advance_to(target);
+    }
+
+    /// Lets the worker enforce now, with `change` applied by another thread
+    /// right after the worker has read the lock settings.
+    fn race_with_worker(&mut self, change: impl FnOnce(&Controller) + Send + 'static) {
+        let before = self.observe();
+        let controller = self.core.controller().clone();
+        self.backend.on_next_call(
+            Operation::GetDefaultInputDevice,
+            Box::new(move || change(&controller)),
+        );
+        let result = self.core.run(self.clock.now());
+        self.finish_enforce(before, result);
+        if let Some(change) = self.backend.take_hook(Operation::GetDefaultInputDevice) {
+            change();
+        }
+        // The menu action enforces once its change is in.
+        self.menu_enforce();
+    }
+
+    pub fn step(&mut self, step: &Step) -> Result<(), String> {
+        match step.clone() {
+            Step::Plug(device) => {
+                let before = self.observe();
+                self.backend.plug(device);
+                self.emit_changes(before);
+            }
+            Step::Unplug(uid) => {
+                let before = self.observe();
+                self.backend
+                    .unplug(&uid)
+                    .ok_or_else(|| format!("no device {}", uid))?;
+                self.emit_changes(before);
+            }
+            Step::SetDefault(uid) => {
+                let before = self.observe();
+                self.backend
+                    .system_set_default(&uid)
+                    .map_err(|_| format!("no device {}", uid))?;
+                self.emit_changes(before);
+            }
+            Step::SetDefaultUnnoticed(uid) => {
+                self.backend
+                    .system_set_default(&uid)
+                    .map_err(|_| format!("no device {}", uid))?;
+            }
+            Step::SetRunning { uid, running } => {
+                self.backend
+                    .set_running(&uid, running)
+                    .map_err(|_| format!("no device {}", uid))?;
+                self.emit(AudioEvent::InputRunningChanged);
+            }
+            Step::SetAlive { uid, alive } => {
+                self.backend
+                    .set_alive(&uid, alive)
+                    .map_err(|_| format!("no device {}", uid))?;
+                if !alive {
+                    self.emit(AudioEvent::DeviceDied(uid));
+                }
+            }
+            Step::Processes(processes) => self.backend.set_processes(processes),
+            Step::Silent(uid) => self.emit(AudioEvent::InputSilent(uid)),
+            Step::Recovered(uid) => self.emit(AudioEvent::InputRecovered(uid)),
+            Step::Sleep => self.emit(AudioEvent::WillSleep),
+            Step::Wake => self.emit(AudioEvent::DidWake),
+            Step::SessionInactive => self.emit(AudioEvent::SessionInactive),
+            Step::SessionActive => self.emit(AudioEvent::SessionActive),
+            Step::Lock(uid) => {
+                self.controller().approve_device(&uid);
+                self.controller().set_locked_uid(Some(uid));
+                self.menu_enforce();
+            }
+            Step::Enable(enabled) => {
+                self.controller().set_enabled(enabled);
+                self.menu_enforce();
+            }
+            Step::LockDuringEnforce(uid) => self.race_with_worker(move |controller| {
+                controller.approve_device(&uid);
+                controller.set_locked_uid(Some(uid));
+            }),
+            Step::EnableDuringEnforce(enabled) => {
+                self.race_with_worker(move |controller| controller.set_enabled(enabled))
+            }
+            Step::Approve(uid) => self.controller().approve_device(&uid),
+            Step::Allow(uid) => {
+                let before = self.observe();
+                let result = self.controller().allow_device(&uid);
+                self.finish_enforce(before, result);
+                self.menu_enforce();
+            }
+            Step::Ignore(uid) => self.controller().ignore_device(&uid),
+            Step::RestartService { default } => {
+                self.backend
+                    .restart_service(default.as_deref())
+                    .map_err(|_| format!("no device {:?}", default))?;
+                // The watcher re-reads the devices when it reinstalls its
+                // listeners, so no diff is reported.
+                self.emit(AudioEvent::ServiceRestarted);
+            }
+            Step::Inject(fault) => self.faults.inject(fault),
+            Step::ClearFaults => self.faults.clear(),
+            Step::UnplugDuringSet(uid) => {
+                let backend = self.backend.clone();
+                self.backend.on_next_call(
+                    Operation::SetDefaultInputDevice,
+                    Box::new(move || {
+                        backend.unplug(&uid);
+                    }),
+                );
+            }
+            Step::WaitMs(ms) => self.wait(Duration::from_millis(ms)),
+            Step::ExpectDefault(uid) => {
+                let actual = self.observe().default.map(|d| d.uid);
+                expect("default", Some(uid), actual)?;
+            }
+            Step::ExpectLockedMissing(expected) => {
+                expect(
+                    "locked_missing",
+                    expected,
+                    self.controller().snapshot().locked_missing,
+                )?;
+            }
+            Step::ExpectPending(expected) => {
+                let pending = self.controller().snapshot().pending.map(|p| p.uid);
+                expect("pending", expected, pending)?;
+            }
+            Step::ExpectDeferred(expected) => {
+                expect("deferred", expected, self.controller().snapshot().deferred)?;
+            }
+            Step::ExpectStabilizing(expected) => {
+                let stabilizing = self.controller().snapshot().stabilizing.is_some();
+                expect("stabilizing", expected, stabilizing)?;
+            }
+            Step::ExpectFailedOverTo(expected) => {
+                expect(
+                    "failed_over_to",
+                    expected,
+                    self.controller().snapshot().failed_over_to,
+                )?;
+            }
+            Step::ExpectError(expected) => {
+                expect("error", expected, self.last_error.clone())?;
+            }
+            Step::ExpectSwitch(expected) => {
+                let last = self.controller().snapshot().last_switch;
+                expect("switch", expected, last.map(|s| s.to_string()))?;
+            }
+            Step::ExpectGaveUp(expected) => {
+                let gave_up = self.controller().snapshot().switch_gave_up;
+                expect("gave_up", expected, gave_up)?;
+            }
+            Step::ExpectMissedNotifications(expected) => {
+                expect("missed notifications", expected, self.missed_notifications)?;
+            }
+            Step::ExpectReinstalls(expected) => {
+                expect("reinstalls", expected, self.reinstalls)?;
+            }
+            Step::ExpectHistory(expected) => {
+                let mut actual: Vec<String> = self
+                    .history
+                    .recent(HISTORY_CAPACITY)
+                    .iter()
+                    .map(|entry| entry.to_string())
+                    .collect();
+                actual.reverse();
+                expect("history", expected, actual)?;
+            }
+            Step::ExpectMetric { series, value } => {
+                let text = self.controller().metrics_text();
+                let actual = text.lines().find_map(|line| {
+                    let rest = line.strip_prefix(series.as_str())?.strip_prefix(' ')?;
+                    rest.parse::<f64>().ok()
+                });
+                expect(&series, Some(value), actual)?;
+            }
+            Step::ExpectInUseBy(expected) => {
+                let backend = self.controller().backend();
+                let actual = audio_backend::in_use_by(
+                    &backend.list_processes().unwrap_or_default(),
+                    &backend.list_input_devices().unwrap_or_default(),
+                );
+                expect("in use by", expected, actual)?;
+            }
+            Step::ExpectLockEvents(expected) => {
+                let actual: Vec<String> = self
+                    .lock_events
+                    .try_iter()
+                    .map(|event| event.to_string())
+                    .collect();
+                expect("lock events", expected, actual)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn expect<T: PartialEq + std::fmt::Debug>(
+    what: &str,
+    expected: T,
+    actual: T,
+) -> Result<(), String> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {} {:?}, got {:?}",
+            what, expected, actual
+        ))
+    }
+}
+
+/// Runs every step and returns the failed expectations. A failed step does
+/// not stop the scenario, so one run reports everything that went wrong.
+pub fn run(scenario: &Scenario) -> Vec<String> {
+    let mut run = Run::start(scenario);
+
+    for (index, step) in scenario.steps.iter().enumerate() {
+        if let Err(message) = run.step(step) {
+            let t_ms = run.clock.elapsed().as_millis();
+            run.failures
+                .push(format!("step {} at {}ms: {}", index + 1, t_ms, message));
+        }
+    }
+    run.failures
+}
+
+pub fn load(path: &Path) -> io::Result<Scenario> {
+    let text = fs::read_to_string(path)?;
+    serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Runs the scenario files in `paths`, expanding directories to the `.json`
+/// files in them, and prints one line per scenario. Returns whether all
+/// passed.
+pub fn run_files(paths: &[PathBuf]) -> io::Result<bool> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    let mut failed = 0usize;
+    for file in &files {
+        let scenario = load(file)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+        let failures = run(&scenario);
+        if failures.is_empty() {
+            println!("ok    {}", scenario.name);
+        } else {
+            failed += 1;
+            println!("FAIL  {} ({})", scenario.name, file.display());
+            for failure in failures {
+                println!("        {}", failure);
+            }
+        }
+    }
+
+    println!("{} scenario(s), {} failed", files.len(), failed);
+    Ok(failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenarios_pass() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+        assert!(run_files(&[dir]).expect("scenario files"));
+    }
+}
@@ -0,0 +1,191 @@
+use crossbeam_channel::Sender;
+
+#[cfg(target_os = "macos")]
+use objc2::rc::Retained;
+#[cfg(target_os = "macos")]
+use objc2::{define_class, msg_send, sel, AllocAnyThread, DefinedClass};
+#[cfg(target_os = "macos")]
+use objc2_app_kit::{
+    NSWorkspace, NSWorkspaceDidWakeNotification, NSWorkspaceSessionDidBecomeActiveNotification,
+    NSWorkspaceSessionDidResignActiveNotification, NSWorkspaceWillSleepNotification,
+};
+#[cfg(target_os = "macos")]
+use objc2_foundation::{NSNotification, NSObject, NSObjectProtocol};
+
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use zbus::blocking::{Connection, MessageIterator};
+#[cfg(target_os = "linux")]
+use zbus::message::Type as MessageType;
+#[cfg(target_os = "linux")]
+use zbus::MatchRule;
+
+use crate::controller::AudioEvent;
+
+#[cfg(target_os = "macos")]
+struct ObserverIvars {
+    tx: Sender<AudioEvent>,
+}
+
+#[cfg(target_os = "macos")]
+define_class!(
+    #[unsafe(super = NSObject)]
+    #[ivars = ObserverIvars]
+    struct SessionObserver;
+
+    unsafe impl NSObjectProtocol for SessionObserver {}
+
+    impl SessionObserver {
+        #[unsafe(method(workspaceWillSleep:))]
+        fn will_sleep(&self, _notification: &NSNotification) {
+            let _ = self.ivars().tx.send(AudioEvent::WillSleep);
+        }
+
+        #[unsafe(method(workspaceDidWake:))]
+        fn did_wake(&self, _notification: &NSNotification) {
+            let _ = self.ivars().tx.send(AudioEvent::DidWake);
+        }
+
+        #[unsafe(method(sessionDidResignActive:))]
+        fn session_resigned(&self, _notification: &NSNotification) {
+            let _ = self.ivars().tx.send(AudioEvent::SessionInactive);
+        }
+
+        #[unsafe(method(sessionDidBecomeActive:))]
+        fn session_activated(&self, _notification: &NSNotification) {
+            let _ = self.ivars().tx.send(AudioEvent::SessionActive);
+        }
+    }
+);
+
+/// Forwards NSWorkspace sleep, wake and fast user switching notifications to
+/// the enforcement worker until dropped.
+#[cfg(target_os = "macos")]
+pub struct SessionEvents {
+    observer: Retained<SessionObserver>,
+}
+
+#[cfg(target_os = "macos")]
+impl SessionEvents {
+    pub fn start(tx: Sender<AudioEvent>) -> Self {
+        let observer = SessionObserver::alloc().set_ivars(ObserverIvars { tx });
+        let observer: Retained<SessionObserver> = unsafe { msg_send![super(observer), init] };
+
+        let center = NSWorkspace::sharedWorkspace().notificationCenter();
+        unsafe {
+            for (name, selector) in [
+                (NSWorkspaceWillSleepNotification, sel!(workspaceWillSleep:)),
+                (NSWorkspaceDidWakeNotification, sel!(workspaceDidWake:)),
+                (
+                    NSWorkspaceSessionDidResignActiveNotification,
+                    sel!(sessionDidResignActive:),
+                ),
+                (
+                    NSWorkspaceSessionDidBecomeActiveNotification,
+                    sel!(sessionDidBecomeActive:),
+                ),
+            ] {
+                center.addObserver_selector_name_object(&observer, selector, Some(name), None);
+            }
+        }
+
+        Self { observer }
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Drop for SessionEvents {
+    fn drop(&mut self) {
+        let center = NSWorkspace::sharedWorkspace().notificationCenter();
+        unsafe { center.removeObserver(&self.observer) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+#[cfg(target_os = "linux")]
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+#[cfg(target_os = "linux")]
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+/// Forwards logind's `PrepareForSleep` signal from the system bus:
+/// `true` before suspending, `false` after resuming.
+#[cfg(target_os = "linux")]
+pub struct SessionEvents {
+    // Keeps the bus connection, and with it the listening thread, alive.
+    _connection: Connection,
+}
+
+#[cfg(target_os = "linux")]
+impl SessionEvents {
+    pub fn start(tx: Sender<AudioEvent>) -> zbus::Result<Self> {
+        let connection = Connection::system()?;
+        // The bus only delivers signals whose sender owns the logind name.
+        let rule = MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .sender(LOGIND_NAME)?
+            .path(LOGIND_PATH)?
+            .interface(LOGIND_MANAGER)?
+            .member("PrepareForSleep")?
+            .build();
+        let signals = MessageIterator::for_match_rule(rule, &connection, None)?;
+
+        std::thread::spawn(move || {
+            for message in signals {
+                let Ok(message) = message else { continue };
+                let Ok(sleeping) = message.body().deserialize::<bool>() else {
+                    continue;
+                };
+                let event = if sleeping {
+                    AudioEvent::WillSleep
+                } else {
+                    AudioEvent::DidWake
+                };
+                if tx.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            _connection: connection,
+        })
+    }
+}
+
+/// Plays logind on the bus `DBUS_SYSTEM_BUS_ADDRESS` points at and checks
+/// that a suspend and resume come through as `WillSleep` and `DidWake`.
+/// Meant for a private bus, e.g. one started with `dbus-run-session`.
+#[cfg(target_os = "linux")]
+pub fn self_test() -> Result<(), String> {
+    if std::env::var_os("DBUS_SYSTEM_BUS_ADDRESS").is_none() {
+        return Err("set DBUS_SYSTEM_BUS_ADDRESS to a private bus first".to_string());
+    }
+
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let _events = SessionEvents::start(tx).map_err(|e| format!("subscribing: {}", e))?;
+
+    let logind = Connection::system().map_err(|e| format!("connecting: {}", e))?;
+    logind
+        .request_name(LOGIND_NAME)
+        .map_err(|e| format!("taking the logind name: {}", e))?;
+
+    for (sleeping, expected) in [(true, "WillSleep"), (false, "DidWake")] {
+        logind
+            .emit_signal(
+                None::<()>,
+                LOGIND_PATH,
+                LOGIND_MANAGER,
+                "PrepareForSleep",
+                &(sleeping,),
+            )
+            .map_err(|e| format!("emitting PrepareForSleep: {}", e))?;
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(event) if format!("{:?}", event) == expected => println!("ok    {}", expected),
+            Ok(event) => return Err(format!("expected {}, got {:?}", expected, event)),
+            Err(_) => return Err(format!("no {} within 5 seconds", expected)),
+        }
+    }
+    Ok(())
+}
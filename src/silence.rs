@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+/// A change in the health of the monitored input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthChange {
+    Silent,
+    Recovered,
+}
+
+/// Tracks RMS level over fixed windows of samples and reports when the input
+/// has been silent for too long, or comes back afterwards. Time is derived
+/// from the number of frames seen, so it works on any sample buffer.
+#[derive(Debug)]
+pub struct SilenceDetector {
+    sample_rate: f64,
+    threshold: f64,
+    window_frames: usize,
+    silence_limit: Duration,
+    sum_squares: f64,
+    window_samples: usize,
+    window_seen: usize,
+    silent_for: Duration,
+    silent: bool,
+}
+
+impl SilenceDetector {
+    /// `threshold_dbfs` is the RMS level below which a window counts as silent.
+    pub fn new(sample_rate: f64, threshold_dbfs: f32, silence_limit: Duration) -> Self {
+        let window_frames = ((sample_rate * 0.1).round() as usize).max(1);
+        Self {
+            sample_rate,
+            threshold: 10f64.powf(f64::from(threshold_dbfs) / 20.0),
+            window_frames,
+            silence_limit,
+            sum_squares: 0.0,
+            window_samples: 0,
+            window_seen: 0,
+            silent_for: Duration::ZERO,
+            silent: false,
+        }
+    }
+
+    /// Feeds interleaved samples with `channels` channels per frame and returns
+    /// the last health change they caused, if any.
+    pub fn process(&mut self, samples: &[f32], channels: usize) -> Option<HealthChange> {
+        let channels = channels.max(1);
+        let mut change = None;
+
+        for frame in samples.chunks(channels) {
+            for sample in frame {
+                let sample = f64::from(*sample);
+                self.sum_squares += sample * sample;
+            }
+            self.window_samples += frame.len();
+            self.window_seen += 1;
+
+            if self.window_seen >= self.window_frames {
+                if let Some(c) = self.finish_window() {
+                    change = Some(c);
+                }
+            }
+        }
+
+        change
+    }
+
+    fn finish_window(&mut self) -> Option<HealthChange> {
+        let rms = (self.sum_squares / self.window_samples.max(1) as f64).sqrt();
+        let window = Duration::from_secs_f64(self.window_seen as f64 / self.sample_rate);
+        self.sum_squares = 0.0;
+        self.window_samples = 0;
+        self.window_seen = 0;
+
+        if rms < self.threshold {
+            self.silent_for += window;
+            if !self.silent && self.silent_for >= self.silence_limit {
+                self.silent = true;
+                return Some(HealthChange::Silent);
+            }
+            return None;
+        }
+
+        self.silent_for = Duration::ZERO;
+        if self.silent {
+            self.silent = false;
+            return Some(HealthChange::Recovered);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48_000.0;
+    const LIMIT: Duration = Duration::from_secs(10);
+
+    /// `secs` of a 440 Hz sine whose RMS level is `dbfs`, on every channel.
+    fn sine(secs: f64, dbfs: f64, channels: usize) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20.0) * 2f64.sqrt();
+        let frames = (secs * RATE) as usize;
+        (0..frames)
+            .flat_map(|n| {
+                let t = n as f64 / RATE;
+                let sample = (amplitude * (2.0 * std::f64::consts::PI * 440.0 * t).sin()) as f32;
+                std::iter::repeat_n(sample, channels)
+            })
+            .collect()
+    }
+
+    fn zeros(secs: f64, channels: usize) -> Vec<f32> {
+        vec![0.0; (secs * RATE) as usize * channels]
+    }
+
+    /// Feeds `samples` in 512-frame buffers, as an IOProc would, and returns
+    /// every change reported.
+    fn feed(detector: &mut SilenceDetector, samples: &[f32], channels: usize) -> Vec<HealthChange> {
+        samples
+            .chunks(512 * channels)
+            .filter_map(|buffer| detector.process(buffer, channels))
+            .collect()
+    }
+
+    #[test]
+    fn a_sine_wave_is_never_silent() {
+        let mut detector = SilenceDetector::new(RATE, -90.0, LIMIT);
+        assert_eq!(feed(&mut detector, &sine(15.0, -20.0, 1), 1), vec![]);
+    }
+
+    #[test]
+    fn zeros_are_silent_once_the_limit_has_passed() {
+        let mut detector = SilenceDetector::new(RATE, -90.0, LIMIT);
+        assert_eq!(feed(&mut detector, &zeros(9.9, 1), 1), vec![]);
+        assert_eq!(
+            feed(&mut detector, &zeros(0.1, 1), 1),
+            vec![HealthChange::Silent]
+        );
+        // Reported once, not for every silent window after.
+        assert_eq!(feed(&mut detector, &zeros(5.0, 1), 1), vec![]);
+    }
+
+    #[test]
+    fn the_threshold_decides_what_counts_as_silent() {
+        let mut below = SilenceDetector::new(RATE, -90.0, LIMIT);
+        assert_eq!(
+            feed(&mut below, &sine(10.0, -95.0, 1), 1),
+            vec![HealthChange::Silent]
+        );
+
+        let mut above = SilenceDetector::new(RATE, -90.0, LIMIT);
+        assert_eq!(feed(&mut above, &sine(10.0, -85.0, 1), 1), vec![]);
+    }
+
+    #[test]
+    fn sound_in_between_restarts_the_silence_count() {
+        let mut detector = SilenceDetector::new(RATE, -90.0, LIMIT);
+        assert_eq!(feed(&mut detector, &zeros(8.0, 1), 1), vec![]);
+        assert_eq!(feed(&mut detector, &sine(0.5, -20.0, 1), 1), vec![]);
+        assert_eq!(feed(&mut detector, &zeros(8.0, 1), 1), vec![]);
+        assert_eq!(
+            feed(&mut detector, &zeros(2.0, 1), 1),
+            vec![HealthChange::Silent]
+        );
+    }
+
+    #[test]
+    fn recovers_when_sound_comes_back() {
+        let mut detector = SilenceDetector::new(RATE, -90.0, LIMIT);
+        assert_eq!(
+            feed(&mut detector, &zeros(10.0, 2), 2),
+            vec![HealthChange::Silent]
+        );
+        assert_eq!(
+            feed(&mut detector, &sine(0.2, -20.0, 2), 2),
+            vec![HealthChange::Recovered]
+        );
+        assert_eq!(feed(&mut detector, &sine(5.0, -20.0, 2), 2), vec![]);
+        // A new stretch of silence has to last the full limit again.
+        assert_eq!(feed(&mut detector, &zeros(9.9, 2), 2), vec![]);
+        assert_eq!(
+            feed(&mut detector, &zeros(0.1, 2), 2),
+            vec![HealthChange::Silent]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio_backend::{
+    AudioBackend, AudioDeviceID, AudioError, AudioProcess, DeviceInfo, Operation,
+};
+
+/// An input device in the simulated device model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimDevice {
+    #[serde(default)]
+    pub id: AudioDeviceID,
+    pub uid: String,
+    pub name: String,
+    #[serde(default = "default_alive")]
+    pub alive: bool,
+    #[serde(default)]
+    pub running: bool,
+}
+
+/// An audio client process in the simulated model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimProcess {
+    #[serde(default)]
+    pub pid: i32,
+    /// Soundstoic itself, e.g. the silence monitor's stream; it is listed
+    /// under this process's PID instead of `pid`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub own: bool,
+    #[serde(default)]
+    pub bundle_id: Option<String>,
+    #[serde(default)]
+    pub running_input: bool,
+    /// UIDs of the input devices it uses; ones not connected are left out.
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+fn default_alive() -> bool {
+    true
+}
+
+type CallHook = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct SimState {
+    devices: Vec<SimDevice>,
+    default_id: Option<AudioDeviceID>,
+    processes: Vec<SimProcess>,
+    set_calls: Vec<(Option<AudioDeviceID>, AudioDeviceID)>,
+    hooks: Vec<(Operation, CallHook)>,
+}
+
+/// In-memory `AudioBackend` for replaying traces and running scenarios off
+/// macOS.
+#[derive(Default)]
+pub struct SimBackend {
+    state: Mutex<SimState>,
+}
+
+impl SimBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the whole device model.
+    pub fn load(&self, devices: Vec<SimDevice>, default_id: Option<AudioDeviceID>) {
+        let mut state = self.state.lock().expect("sim state");
+        state.devices = devices;
+        state.default_id = default_id;
+    }
+
+    /// Adds a device, giving it the next free ID when `id` is 0.
+    pub fn plug(&self, mut device: SimDevice) -> SimDevice {
+        let mut state = self.state.lock().expect("sim state");
+        if device.id == 0 {
+            device.id = state.devices.iter().map(|d| d.id).max().unwrap_or(0) + 1;
+        }
+        state.devices.retain(|d| d.uid != device.uid);
+        state.devices.push(device.clone());
+        device
+    }
+
+    /// Removes a device. Like macOS, a removed default falls back to the
+    /// first remaining device.
+    pub fn unplug(&self, uid: &str) -> Option<SimDevice> {
+        let mut state = self.state.lock().expect("sim state");
+        let index = state.devices.iter().position(|d| d.uid == uid)?;
+        let device = state.devices.remove(index);
+        if state.default_id == Some(device.id) {
+            state.default_id = state.devices.first().map(|d| d.id);
+        }
+        Some(device)
+    }
+
+    /// Changes the default input the way the system does, without counting
+    /// it as a `set_default_input_device` call.
+    pub fn system_set_default(&self, uid: &str) -> Result<(), AudioError> {
+        let mut state = self.state.lock().expect("sim state");
+        let id = state
+            .devices
+            .iter()
+            .find(|d| d.uid == uid)
+            .map(|d| d.id)
+            .ok_or(AudioError::NotFound)?;
+        state.default_id = Some(id);
+        Ok(())
+    }
+
+    /// Restarts the audio server: every device comes back under a new ID,
+    /// handed out in reverse so that stale IDs name other devices. The
+    /// default input is kept, or becomes `default` if given.
+    pub fn restart_service(&self, default: Option<&str>) -> Result<(), AudioError> {
+        let mut state = self.state.lock().expect("sim state");
+        let default_uid = match default {
+            Some(uid) => Some(uid.to_string()),
+            None => state
+                .default_id
+                .and_then(|id| state.devices.iter().find(|d| d.id == id))
+                .map(|d| d.uid.clone()),
+        };
+
+        let count = state.devices.len() as AudioDeviceID;
+        for (index, device) in state.devices.iter_mut().enumerate() {
+            device.id = count - index as AudioDeviceID;
+        }
+
+        state.default_id = match default_uid {
+            Some(uid) => Some(
+                state
+                    .devices
+                    .iter()
+                    .find(|d| d.uid == uid)
+                    .map(|d| d.id)
+                    .ok_or(AudioError::NotFound)?,
+            ),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Every `set_default_input_device` call since the last take, as
+    /// `(previous default, new default)`.
+    pub fn take_set_calls(&self) -> Vec<(Option<AudioDeviceID>, AudioDeviceID)> {
+        std::mem::take(&mut self.state.lock().expect("sim state").set_calls)
+    }
+
+    /// Runs `hook` at the start of the next `op` call. Lets scenarios
+    /// interleave other changes with `enforce`. Only the default input
+    /// operations run hooks.
+    pub fn on_next_call(&self, op: Operation, hook: CallHook) {
+        self.state.lock().expect("sim state").hooks.push((op, hook));
+    }
+
+    /// Removes the hook for `op` if it has not run yet.
+    pub fn take_hook(&self, op: Operation) -> Option<CallHook> {
+        let mut state = self.state.lock().expect("sim state");
+        let index = state.hooks.iter().position(|(o, _)| *o == op)?;
+        Some(state.hooks.remove(index).1)
+    }
+
+    /// Replaces the audio client processes.
+    pub fn set_processes(&self, processes: Vec<SimProcess>) {
+        self.state.lock().expect("sim state").processes = processes;
+    }
+
+    pub fn set_alive(&self, uid: &str, alive: bool) -> Result<(), AudioError> {
+        self.update(uid, |d| d.alive = alive)
+    }
+
+    pub fn set_running(&self, uid: &str, running: bool) -> Result<(), AudioError> {
+        self.update(uid, |d| d.running = running)
+    }
+
+    fn update(&self, uid: &str, f: impl FnOnce(&mut SimDevice)) -> Result<(), AudioError> {
+        let mut state = self.state.lock().expect("sim state");
+        state
+            .devices
+            .iter_mut()
+            .find(|d| d.uid == uid)
+            .map(f)
+            .ok_or(AudioError::NotFound)
+    }
+
+    fn with_device<T>(
+        &self,
+        device_id: AudioDeviceID,
+        f: impl FnOnce(&SimDevice) -> T,
+    ) -> Result<T, AudioError> {
+        let state = self.state.lock().expect("sim state");
+        state
+            .devices
+            .iter()
+            .find(|d| d.id == device_id)
+            .map(f)
+            .ok_or(AudioError::NotFound)
+    }
+}
+
+/// Captures the current device model of any backend, e.g. to record it.
+pub fn capture_devices(backend: &dyn AudioBackend) -> (Option<AudioDeviceID>, Vec<SimDevice>) {
+    let devices = backend
+        .list_input_devices()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|d| SimDevice {
+            alive: backend.device_is_alive(d.id).unwrap_or(true),
+            running: backend.device_is_running_somewhere(d.id).unwrap_or(false),
+            id: d.id,
+            uid: d.uid,
+            name: d.name,
+        })
+        .collect();
+    (backend.get_default_input_device().ok(), devices)
+}
+
+impl AudioBackend for SimBackend {
+    fn list_input_devices(&self) -> Result<Vec<DeviceInfo>, AudioError> {
+        let state = self.state.lock().expect("sim state");
+        Ok(state
+            .devices
+            .iter()
+            .map(|d| DeviceInfo {
+                id: d.id,
+                uid: d.uid.clone(),
+                name: d.name.clone(),
+                input_channels: 1,
+            })
+            .collect())
+    }
+
+    fn get_default_input_device(&self) -> Result<AudioDeviceID, AudioError> {
+        if let Some(hook) = self.take_hook(Operation::GetDefaultInputDevice) {
+            hook();
+        }
+        let state = self.state.lock().expect("sim state");
+        state.default_id.ok_or(AudioError::NotFound)
+    }
+
+    fn set_default_input_device(&self, device_id: AudioDeviceID) -> Result<(), AudioError> {
+        if let Some(hook) = self.take_hook(Operation::SetDefaultInputDevice) {
+            hook();
+        }
+        let mut state = self.state.lock().expect("sim state");
+        if !state.devices.iter().any(|d| d.id == device_id) {
+            return Err(AudioError::NotFound);
+        }
+        let previous = state.default_id.replace(device_id);
+        state.set_calls.push((previous, device_id));
+        Ok(())
+    }
+
+    fn device_name_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError> {
+        self.with_device(device_id, |d| d.name.clone())
+    }
+
+    fn device_uid_by_id(&self, device_id: AudioDeviceID) -> Result<String, AudioError> {
+        self.with_device(device_id, |d| d.uid.clone())
+    }
+
+    fn device_id_for_uid(&self, uid: &str) -> Result<AudioDeviceID, AudioError> {
+        let state = self.state.lock().expect("sim state");
+        state
+            .devices
+            .iter()
+            .find(|d| d.uid == uid)
+            .map(|d| d.id)
+            .ok_or(AudioError::NotFound)
+    }
+
+    fn device_is_alive(&self, device_id: AudioDeviceID) -> Result<bool, AudioError> {
+        self.with_device(device_id, |d| d.alive)
+    }
+
+    fn device_is_running_somewhere(&self, device_id: AudioDeviceID) -> Result<bool, AudioError> {
+        self.with_device(device_id, |d| d.running)
+    }
+
+    fn list_processes(&self) -> Result<Vec<AudioProcess>, AudioError> {
+        let state = self.state.lock().expect("sim state");
+        Ok(state
+            .processes
+            .iter()
+            .map(|p| AudioProcess {
+                pid: if p.own {
+                    std::process::id() as i32
+                } else {
+                    p.pid
+                },
+                bundle_id: p.bundle_id.clone(),
+                is_running_input: p.running_input,
+                devices: p
+                    .devices
+                    .iter()
+                    .filter_map(|uid| state.devices.iter().find(|d| d.uid == *uid))
+                    .map(|d| d.id)
+                    .collect(),
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Span, Subscriber};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+use crate::config::Config;
+use crate::logging::RotatingFile;
+
+/// Notification spans waiting for the worker. Older ones are dropped, e.g.
+/// while the worker is paused for sleep.
+const PENDING_NOTIFICATIONS: usize = 64;
+
+/// Spans of HAL notifications the listener has passed on, keyed by when the
+/// notification arrived, which the events it produced carry as `at`.
+static NOTIFICATIONS: Mutex<VecDeque<(Instant, Span)>> = Mutex::new(VecDeque::new());
+
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Called by the listener once it has sent the events for the notification
+/// that arrived `at`, so the worker can continue its trace.
+pub fn notification_sent(at: Instant, span: &Span) {
+    if span.is_disabled() {
+        return;
+    }
+    let mut pending = NOTIFICATIONS.lock().expect("notification spans");
+    if pending.len() == PENDING_NOTIFICATIONS {
+        pending.pop_front();
+    }
+    pending.push_back((at, span.clone()));
+}
+
+/// The span of the notification that arrived `at`, if it is still waiting.
+/// It stays open as long as spans started under it are.
+pub fn take_notification(at: Instant) -> Option<Span> {
+    let mut pending = NOTIFICATIONS.lock().expect("notification spans");
+    let index = pending.iter().position(|(notified, _)| *notified == at)?;
+    pending.remove(index).map(|(_, span)| span)
+}
+
+/// The layer that exports spans as `cfg` asks: over OTLP to `otlp_endpoint`,
+/// or else to the JSON-lines file at `spans_path`. Only spans are exported;
+/// log events stay in the log. What went wrong is added to `problems`, to be
+/// logged once logging is up.
+pub fn layer(cfg: &Config, problems: &mut Vec<String>) -> Option<BoxedLayer> {
+    let layer = otlp_layer(cfg, problems).or_else(|| {
+        let path = cfg.spans_path.as_ref()?;
+        match RotatingFile::open(path.clone()) {
+            Ok(file) => Some(JsonSpans::new(file).boxed()),
+            Err(e) => {
+                problems.push(format!("could not open {}: {}", path.display(), e));
+                None
+            }
+        }
+    })?;
+    Some(layer.with_filter(filter_fn(|meta| meta.is_span())).boxed())
+}
+
+#[cfg(feature = "otlp")]
+static OTLP_PROVIDER: std::sync::OnceLock<opentelemetry_sdk::trace::SdkTracerProvider> =
+    std::sync::OnceLock::new();
+
+#[cfg(feature = "otlp")]
+fn otlp_layer(cfg: &Config, problems: &mut Vec<String>) -> Option<BoxedLayer> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+
+    let endpoint = cfg.otlp_endpoint.as_deref()?;
+    let exporter = match SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            problems.push(format!("OTLP export to {} unavailable: {}", endpoint, e));
+            return None;
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("soundstoic").build())
+        .build();
+    let tracer = provider.tracer("soundstoic");
+    let _ = OTLP_PROVIDER.set(provider);
+    Some(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp_layer(cfg: &Config, problems: &mut Vec<String>) -> Option<BoxedLayer> {
+    if cfg.otlp_endpoint.is_some() {
+        problems.push(
+            "otlp_endpoint is set, but this build has no OTLP export (feature otlp)".to_string(),
+        );
+    }
+    None
+}
+
+/// Sends spans still buffered for OTLP export.
+pub fn flush() {
+    #[cfg(feature = "otlp")]
+    if let Some(provider) = OTLP_PROVIDER.get() {
+        let _ = provider.force_flush();
+    }
+}
+
+/// A new id for each trace, i.e. each span without a parent.
+static NEXT_TRACE: AtomicU64 = AtomicU64::new(1);
+
+/// What `JsonSpans` keeps about an open span.
+struct SpanData {
+    trace: u64,
+    start: SystemTime,
+    started: Instant,
+    fields: Map<String, Value>,
+    follows_from: Vec<u64>,
+}
+
+/// Writes each span as one JSON line when it closes, with its parent, the
+/// spans it follows from, and its fields.
+struct JsonSpans {
+    out: Mutex<RotatingFile>,
+}
+
+impl JsonSpans {
+    fn new(file: RotatingFile) -> Self {
+        Self {
+            out: Mutex::new(file),
+        }
+    }
+}
+
+impl<S> Layer<S> for JsonSpans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let trace = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanData>().map(|data| data.trace))
+            .unwrap_or_else(|| NEXT_TRACE.fetch_add(1, Ordering::Relaxed));
+        let mut fields = Map::new();
+        attrs.record(&mut JsonFields(&mut fields));
+        span.extensions_mut().insert(SpanData {
+            trace,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            fields,
+            follows_from: Vec::new(),
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut JsonFields(&mut data.fields));
+        }
+    }
+
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            data.follows_from.push(follows.into_u64());
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let extensions = span.extensions();
+        let Some(data) = extensions.get::<SpanData>() else {
+            return;
+        };
+        let start_us = data
+            .start
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let line = json!({
+            "trace": data.trace,
+            "id": id.into_u64(),
+            "parent": span.parent().map(|parent| parent.id().into_u64()),
+            "follows_from": data.follows_from,
+            "name": span.name(),
+            "target": span.metadata().target(),
+            "start_us": start_us,
+            "duration_us": data.started.elapsed().as_micros() as u64,
+            "fields": data.fields,
+        });
+        let mut out = self.out.lock().expect("span file");
+        let _ = writeln!(out, "{}", line);
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), json!(format!("{:?}", value)));
+    }
+}
@@ -0,0 +1,403 @@
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+
+use crate::config::{self, ConfigStore};
+use crate::{history, logging};
+
+/// History entries included, newest last.
+const HISTORY_ENTRIES: usize = 200;
+/// Only the end of each log file is included.
+const LOG_TAIL_BYTES: u64 = 2 * 1024 * 1024;
+/// Log files included: the current one and the newest rotated one.
+const LOG_FILES: usize = 2;
+
+/// Directory every file in the archive is under.
+const ROOT: &str = "soundstoic-support";
+
+/// `soundstoic-support-YYYYMMDD-HHMMSS.tar.gz` for the local time `at_ms`.
+pub fn file_name(at_ms: u64) -> String {
+    let stamp: String = history::local_time(at_ms)
+        .chars()
+        .filter_map(|c| match c {
+            '-' | ':' => None,
+            ' ' => Some('-'),
+            c => Some(c),
+        })
+        .collect();
+    format!("soundstoic-support-{}.tar.gz", stamp)
+}
+
+/// Writes a gzipped tar archive to `path` with what is needed to triage a
+/// lock failure: the config, recent history and logs, the device
+/// inspection, and app, OS and login item details. The login name, full
+/// name and home directory are replaced throughout. A part that cannot be
+/// collected is listed under `problems` in `system.json` instead.
+pub fn write(path: &Path, config: &ConfigStore) -> Result<(), String> {
+    let redact = Redactor::for_current_user();
+    let now_ms = history::now_ms();
+    let mut problems = Vec::new();
+    let mut files: Vec<(String, String)> = Vec::new();
+
+    match fs::read_to_string(config.path()) {
+        Ok(text) => files.push(("config.json".to_string(), mask_secrets(text))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            problems.push("no config file, the defaults are in use".to_string());
+            let defaults = serde_json::to_string_pretty(&config.get()).unwrap_or_default();
+            files.push(("config.json".to_string(), mask_secrets(defaults)));
+        }
+        Err(e) => problems.push(format!("config: {}", e)),
+    }
+    if let Some(e) = config.load_error() {
+        problems.push(format!("config file ignored: {}", e));
+    }
+
+    match history::load(&history::default_path()) {
+        Ok(entries) => {
+            let recent = &entries[entries.len().saturating_sub(HISTORY_ENTRIES)..];
+            let lines: Vec<String> = recent
+                .iter()
+                .filter_map(|entry| serde_json::to_string(entry).ok())
+                .collect();
+            files.push(("history.jsonl".to_string(), lines.join("\n") + "\n"));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => problems.push(format!("history: {}", e)),
+    }
+
+    let log = logging::log_path();
+    for index in 0..LOG_FILES {
+        let mut path = log.clone().into_os_string();
+        if index > 0 {
+            path.push(format!(".{}", index));
+        }
+        let path = PathBuf::from(path);
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match read_tail(&path, LOG_TAIL_BYTES) {
+            Ok(text) => files.push((format!("logs/{}", name), text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => problems.push(format!("log {}: {}", name, e)),
+        }
+    }
+
+    match devices() {
+        Ok(dump) => files.push((
+            "devices.json".to_string(),
+            serde_json::to_string_pretty(&dump).unwrap_or_default(),
+        )),
+        Err(e) => problems.push(format!("devices: {}", e)),
+    }
+
+    let (bundle_id, bundle_problem) = match bundle_id() {
+        Ok(id) => (Some(id), None),
+        Err(e) => (None, Some(e)),
+    };
+    problems.extend(bundle_problem);
+    let system = json!({
+        "generated_at": history::local_time(now_ms),
+        "soundstoic_version": env!("CARGO_PKG_VERSION"),
+        "os": os_version(),
+        "arch": std::env::consts::ARCH,
+        "bundle_id": bundle_id,
+        "start_at_login": start_at_login(),
+        "config_path": config.path(),
+        "problems": problems,
+    });
+    files.push((
+        "system.json".to_string(),
+        serde_json::to_string_pretty(&system).unwrap_or_default(),
+    ));
+
+    write_archive(path, &files, &redact, now_ms / 1000)
+        .map_err(|e| format!("could not write {}: {}", path.display(), e))
+}
+
+/// `soundstoic support-bundle [-o PATH]`.
+pub fn run(args: &[String], config: &ConfigStore) -> Result<(), String> {
+    let path = match args {
+        [] => PathBuf::from(file_name(history::now_ms())),
+        [flag, path] if flag == "-o" => PathBuf::from(path),
+        _ => return Err("usage: soundstoic support-bundle [-o PATH]".to_string()),
+    };
+    write(&path, config)?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+fn write_archive(
+    path: &Path,
+    files: &[(String, String)],
+    redact: &Redactor,
+    mtime: u64,
+) -> io::Result<()> {
+    let gz = GzEncoder::new(File::create(path)?, Compression::default());
+    let mut tar = tar::Builder::new(gz);
+    for (name, text) in files {
+        let data = redact.text(text);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        tar.append_data(&mut header, format!("{}/{}", ROOT, name), data.as_bytes())?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// The config file with its secrets masked. Text that is not JSON is kept
+/// as is, since it is what failed to load.
+fn mask_secrets(text: String) -> String {
+    let Ok(mut value) = serde_json::from_str::<Value>(&text) else {
+        return text;
+    };
+    config::mask_secrets(&mut value);
+    serde_json::to_string_pretty(&value).unwrap_or(text)
+}
+
+/// The last `max` bytes of `path`, starting at a line boundary.
+fn read_tail(path: &Path, max: u64) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let start = len.saturating_sub(max);
+    file.seek(SeekFrom::Start(start))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    if start > 0 {
+        let first_line = bytes.iter().position(|b| *b == b'\n').map_or(0, |i| i + 1);
+        bytes.drain(..first_line);
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(target_os = "macos")]
+fn devices() -> Result<Value, String> {
+    crate::inspect::dump(None)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn devices() -> Result<Value, String> {
+    Err("device inspection requires macOS".to_string())
+}
+
+#[cfg(target_os = "macos")]
+fn start_at_login() -> Option<bool> {
+    Some(crate::autostart::is_enabled())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn start_at_login() -> Option<bool> {
+    None
+}
+
+/// `CFBundleIdentifier` from the `Info.plist` of the app bundle the binary
+/// runs from.
+fn bundle_id() -> Result<String, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("bundle id: {}", e))?;
+    // Soundstoic.app/Contents/MacOS/soundstoic
+    let plist = exe
+        .parent()
+        .and_then(Path::parent)
+        .map(|contents| contents.join("Info.plist"))
+        .filter(|plist| plist.exists())
+        .ok_or("bundle id: not running from an app bundle")?;
+    let text = fs::read_to_string(&plist).map_err(|e| format!("bundle id: {}", e))?;
+    plist_string(&text, "CFBundleIdentifier")
+        .ok_or_else(|| "bundle id: no CFBundleIdentifier in Info.plist".to_string())
+}
+
+/// The `<string>` after `<key>key</key>` in an XML property list.
+fn plist_string(text: &str, key: &str) -> Option<String> {
+    let after_key = text.split(&format!("<key>{}</key>", key)).nth(1)?;
+    let value = after_key.trim_start().strip_prefix("<string>")?;
+    Some(value.split("</string>").next()?.trim().to_string())
+}
+
+#[cfg(target_os = "macos")]
+fn os_version() -> Value {
+    json!({
+        "product_version": sysctl_string(c"kern.osproductversion"),
+        "build": sysctl_string(c"kern.osversion"),
+        "kernel": uname_release(),
+    })
+}
+
+#[cfg(not(target_os = "macos"))]
+fn os_version() -> Value {
+    json!({
+        "os": std::env::consts::OS,
+        "kernel": uname_release(),
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn sysctl_string(name: &CStr) -> Option<String> {
+    let mut buf = [0u8; 256];
+    let mut len = buf.len();
+    let status = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+    let value = CStr::from_bytes_until_nul(&buf[..len]).ok()?;
+    Some(value.to_string_lossy().into_owned())
+}
+
+fn uname_release() -> Option<String> {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return None;
+    }
+    let release = unsafe { CStr::from_ptr(name.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+/// Replaces what identifies the user: their home directory with `~`, their
+/// login and full name with `<user>`, and any other home directory's owner.
+struct Redactor {
+    home: Option<String>,
+    /// Matched as whole words only, so a short login does not hit the
+    /// middle of a device name.
+    names: Vec<String>,
+}
+
+impl Redactor {
+    fn for_current_user() -> Self {
+        let home = dirs::home_dir()
+            .map(|home| home.to_string_lossy().trim_end_matches('/').to_string())
+            .filter(|home| home.len() > 1);
+
+        let mut names = Vec::new();
+        let pw = unsafe { libc::getpwuid(libc::getuid()) };
+        if !pw.is_null() {
+            let login = unsafe { CStr::from_ptr((*pw).pw_name) };
+            names.push(login.to_string_lossy().into_owned());
+            let gecos = unsafe { (*pw).pw_gecos };
+            if !gecos.is_null() {
+                let gecos = unsafe { CStr::from_ptr(gecos) }.to_string_lossy();
+                // "Full Name,office,phone,..."
+                let full = gecos.split(',').next().unwrap_or("").trim().to_string();
+                names.extend(full.split_whitespace().map(str::to_string));
+                names.push(full);
+            }
+        }
+        if let Ok(user) = std::env::var("USER") {
+            names.push(user);
+        }
+        names.retain(|name| name.chars().count() > 1);
+        // Longest first, so a full name goes before its parts.
+        names.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        names.dedup();
+        Self { home, names }
+    }
+
+    fn text(&self, text: &str) -> String {
+        let mut out = match &self.home {
+            Some(home) => text.replace(home.as_str(), "~"),
+            None => text.to_string(),
+        };
+        for prefix in ["/Users/", "/home/"] {
+            out = redact_home_dirs(&out, prefix);
+        }
+        for name in &self.names {
+            out = replace_word(&out, name, "<user>");
+        }
+        out
+    }
+}
+
+/// Replaces the directory name after each `prefix`, e.g. `/Users/alice`.
+fn redact_home_dirs(text: &str, prefix: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(prefix) {
+        let (before, after) = rest.split_at(index + prefix.len());
+        out.push_str(before);
+        let end = after
+            .find(|c: char| !(c.is_alphanumeric() || matches!(c, '.' | '_' | '-')))
+            .unwrap_or(after.len());
+        let owner = &after[..end];
+        if owner.is_empty() || owner == "Shared" {
+            out.push_str(owner);
+        } else {
+            out.push_str("<user>");
+        }
+        rest = &after[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn replace_word(text: &str, word: &str, with: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(word) {
+        let before = rest[..index].chars().next_back();
+        let after = rest[index + word.len()..].chars().next();
+        let bounded = |c: Option<char>| !c.is_some_and(char::is_alphanumeric);
+        out.push_str(&rest[..index]);
+        if bounded(before) && bounded(after) {
+            out.push_str(with);
+        } else {
+            out.push_str(word);
+        }
+        rest = &rest[index + word.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked(config: Value) -> Value {
+        serde_json::from_str(&mask_secrets(config.to_string())).unwrap()
+    }
+
+    #[test]
+    fn every_secret_is_masked() {
+        let config = masked(json!({
+            "lock_enabled": true,
+            "hooks": { "locked": "curl -H 'Authorization: Bearer abc' https://example.com", "reverted": "say hi" },
+            "mqtt": { "host": "broker.local", "username": "me", "password": "hunter2" },
+        }));
+        assert_eq!(config["hooks"]["locked"], config::REDACTED);
+        assert_eq!(config["hooks"]["reverted"], config::REDACTED);
+        assert_eq!(config["mqtt"]["username"], config::REDACTED);
+        assert_eq!(config["mqtt"]["password"], config::REDACTED);
+        assert_eq!(config["mqtt"]["host"], "broker.local");
+        assert_eq!(config["lock_enabled"], true);
+    }
+
+    #[test]
+    fn hooks_are_masked_without_mqtt() {
+        let config = masked(json!({ "hooks": { "locked": "notify --token abc" } }));
+        assert_eq!(config["hooks"]["locked"], config::REDACTED);
+        assert!(config.get("mqtt").is_none());
+    }
+
+    #[test]
+    fn unset_credentials_stay_unset() {
+        let config = masked(json!({ "mqtt": { "host": "broker.local", "password": null } }));
+        assert_eq!(config["mqtt"]["password"], Value::Null);
+        assert!(config["mqtt"].get("username").is_none());
+    }
+
+    #[test]
+    fn text_that_is_not_json_is_kept() {
+        let text = "{ \"lock_enabled\": tru".to_string();
+        assert_eq!(mask_secrets(text.clone()), text);
+    }
+}
@@ -0,0 +1,464 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio_backend::{AudioBackend, AudioDeviceID, AudioError};
+use crate::clock::{Clock, VirtualClock};
+use crate::config::{self, Config};
+use crate::controller::{
+    AudioEvent, Controller, DeviceRef, EnforceResult, LockSnapshot, WorkerCore,
+};
+use crate::sim_backend::{capture_devices, SimBackend, SimDevice};
+
+/// One line of a trace file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TraceRecord {
+    Start {
+        config: Box<Config>,
+    },
+    Event {
+        t_ms: u64,
+        event: TraceEvent,
+    },
+    Devices {
+        t_ms: u64,
+        default_id: Option<AudioDeviceID>,
+        devices: Vec<SimDevice>,
+    },
+    Decision {
+        t_ms: u64,
+        enabled: bool,
+        locked_uid: Option<String>,
+        outcome: Outcome,
+        /// Made by `Controller::enforce_now` outside the worker, e.g. at
+        /// startup or for a menu change.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        immediate: bool,
+    },
+    /// The watchdog found the lock out of place without having been told of
+    /// a change. `reinstalled` if the listeners were installed again.
+    MissedNotification {
+        t_ms: u64,
+        reinstalled: bool,
+    },
+}
+
+/// `AudioEvent` without its `Instant`, which the record's `t_ms` replaces.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceEvent {
+    DefaultInputChanged {
+        previous: Option<DeviceRef>,
+        current: Option<DeviceRef>,
+    },
+    DevicesChanged,
+    DeviceAdded {
+        device: DeviceRef,
+    },
+    DeviceRemoved {
+        device: DeviceRef,
+    },
+    ServiceRestarted,
+    InputRunningChanged,
+    InputSilent {
+        uid: String,
+    },
+    InputRecovered {
+        uid: String,
+    },
+    DeviceDied {
+        uid: String,
+    },
+    StreamConfigChanged {
+        uid: String,
+    },
+    SettingsChanged,
+    WillSleep,
+    DidWake,
+    SessionInactive,
+    SessionActive,
+}
+
+impl TraceEvent {
+    pub fn from_event(event: &AudioEvent) -> Self {
+        match event.clone() {
+            AudioEvent::DefaultInputChanged {
+                previous, current, ..
+            } => TraceEvent::DefaultInputChanged { previous, current },
+            AudioEvent::DevicesChanged { .. } => TraceEvent::DevicesChanged,
+            AudioEvent::DeviceAdded { device, .. } => TraceEvent::DeviceAdded { device },
+            AudioEvent::DeviceRemoved { device, .. } => TraceEvent::DeviceRemoved { device },
+            AudioEvent::ServiceRestarted => TraceEvent::ServiceRestarted,
+            AudioEvent::SettingsChanged => TraceEvent::SettingsChanged,
+            AudioEvent::WillSleep => TraceEvent::WillSleep,
+            AudioEvent::DidWake => TraceEvent::DidWake,
+            AudioEvent::SessionInactive => TraceEvent::SessionInactive,
+            AudioEvent::SessionActive => TraceEvent::SessionActive,
+            AudioEvent::InputRunningChanged => TraceEvent::InputRunningChanged,
+            AudioEvent::InputSilent(uid) => TraceEvent::InputSilent { uid },
+            AudioEvent::InputRecovered(uid) => TraceEvent::InputRecovered { uid },
+            AudioEvent::DeviceDied(uid) => TraceEvent::DeviceDied { uid },
+            AudioEvent::StreamConfigChanged(uid) => TraceEvent::StreamConfigChanged { uid },
+        }
+    }
+
+    pub fn to_event(&self, at: Instant) -> AudioEvent {
+        match self.clone() {
+            TraceEvent::DefaultInputChanged { previous, current } => {
+                AudioEvent::DefaultInputChanged {
+                    previous,
+                    current,
+                    at,
+                }
+            }
+            TraceEvent::DevicesChanged => AudioEvent::DevicesChanged { at },
+            TraceEvent::DeviceAdded { device } => AudioEvent::DeviceAdded { device, at },
+            TraceEvent::DeviceRemoved { device } => AudioEvent::DeviceRemoved { device, at },
+            TraceEvent::ServiceRestarted => AudioEvent::ServiceRestarted,
+            TraceEvent::SettingsChanged => AudioEvent::SettingsChanged,
+            TraceEvent::WillSleep => AudioEvent::WillSleep,
+            TraceEvent::DidWake => AudioEvent::DidWake,
+            TraceEvent::SessionInactive => AudioEvent::SessionInactive,
+            TraceEvent::SessionActive => AudioEvent::SessionActive,
+            TraceEvent::InputRunningChanged => AudioEvent::InputRunningChanged,
+            TraceEvent::InputSilent { uid } => AudioEvent::InputSilent(uid),
+            TraceEvent::InputRecovered { uid } => AudioEvent::InputRecovered(uid),
+            TraceEvent::DeviceDied { uid } => AudioEvent::DeviceDied(uid),
+            TraceEvent::StreamConfigChanged { uid } => AudioEvent::StreamConfigChanged(uid),
+        }
+    }
+}
+
+/// What a single `Controller::enforce` call decided.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Outcome {
+    pub changed: bool,
+    pub locked_missing: bool,
+    pub pending_approval: bool,
+    pub deferred: bool,
+    pub failed_over_to: Option<String>,
+    pub default_uid: Option<String>,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub switch: Option<String>,
+}
+
+impl Outcome {
+    fn new(result: &Result<EnforceResult, AudioError>, backend: &dyn AudioBackend) -> Self {
+        let default_uid = backend
+            .get_default_input_device()
+            .and_then(|id| backend.device_uid_by_id(id))
+            .ok();
+        match result {
+            Ok(r) => Self {
+                changed: r.changed,
+                locked_missing: r.locked_missing,
+                pending_approval: r.pending_approval,
+                deferred: r.deferred,
+                failed_over_to: r.failed_over_to.clone(),
+                default_uid,
+                error: None,
+                switch: r.switch.as_ref().map(|s| s.to_string()),
+            },
+            Err(e) => Self {
+                default_uid,
+                error: Some(format!("{:?}", e)),
+                ..Self::default()
+            },
+        }
+    }
+}
+
+/// Appends events, device snapshots and enforce decisions to a JSON-lines
+/// trace file.
+pub struct TraceRecorder {
+    started: Instant,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl TraceRecorder {
+    pub fn create(path: &Path, config: &Config) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let recorder = Self {
+            started: Instant::now(),
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        };
+        recorder.write(&TraceRecord::Start {
+            config: Box::new(masked(config)),
+        });
+        Ok(recorder)
+    }
+
+    pub fn event(&self, event: &AudioEvent) {
+        let at = event.at().unwrap_or_else(Instant::now);
+        self.write(&TraceRecord::Event {
+            t_ms: self.t_ms(at),
+            event: TraceEvent::from_event(event),
+        });
+    }
+
+    pub fn devices(&self, backend: &dyn AudioBackend) {
+        let (default_id, devices) = capture_devices(backend);
+        self.write(&TraceRecord::Devices {
+            t_ms: self.t_ms(Instant::now()),
+            default_id,
+            devices,
+        });
+    }
+
+    /// `before` is the lock state `enforce` started from.
+    pub fn decision(
+        &self,
+        before: &LockSnapshot,
+        result: &Result<EnforceResult, AudioError>,
+        backend: &dyn AudioBackend,
+    ) {
+        self.write_decision(before, result, backend, false);
+    }
+
+    /// A decision made outside the worker, by `Controller::enforce_now`.
+    pub fn immediate_decision(
+        &self,
+        before: &LockSnapshot,
+        result: &Result<EnforceResult, AudioError>,
+        backend: &dyn AudioBackend,
+    ) {
+        self.write_decision(before, result, backend, true);
+    }
+
+    fn write_decision(
+        &self,
+        before: &LockSnapshot,
+        result: &Result<EnforceResult, AudioError>,
+        backend: &dyn AudioBackend,
+        immediate: bool,
+    ) {
+        self.write(&TraceRecord::Decision {
+            t_ms: self.t_ms(Instant::now()),
+            enabled: before.enabled,
+            locked_uid: before.locked_uid.clone(),
+            outcome: Outcome::new(result, backend),
+            immediate,
+        });
+    }
+
+    pub fn missed_notification(&self, reinstalled: bool) {
+        self.write(&TraceRecord::MissedNotification {
+            t_ms: self.t_ms(Instant::now()),
+            reinstalled,
+        });
+    }
+
+    fn t_ms(&self, at: Instant) -> u64 {
+        at.saturating_duration_since(self.started).as_millis() as u64
+    }
+
+    fn write(&self, record: &TraceRecord) {
+        let mut out = self.out.lock().expect("trace file");
+        if serde_json::to_writer(&mut *out, record).is_ok() {
+            let _ = out.write_all(b"\n");
+            let _ = out.flush();
+        }
+    }
+}
+
+/// `config` with its secrets masked as in a support bundle, since a trace is
+/// meant to be attached to a bug report. Replay does not use them.
+fn masked(config: &Config) -> Config {
+    let Ok(mut value) = serde_json::to_value(config) else {
+        return Config::default();
+    };
+    config::mask_secrets(&mut value);
+    serde_json::from_value(value).unwrap_or_default()
+}
+
+/// Feeds a recorded trace through the enforcement worker's core over
+/// `SimBackend`, on a virtual clock moved to each record's time, and prints
+/// each decision next to the recorded one. Returns how many differ.
+pub fn replay(path: &Path) -> io::Result<usize> {
+    let reader = BufReader::new(File::open(path)?);
+    let backend = Arc::new(SimBackend::new());
+    let clock = Arc::new(VirtualClock::new());
+    let started = clock.now();
+    let at = |t_ms: u64| {
+        clock.advance_to(started + Duration::from_millis(t_ms));
+        clock.now()
+    };
+    let mut core: Option<WorkerCore> = None;
+    let mut mismatches = 0usize;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: TraceRecord = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {}", index + 1, e),
+            )
+        })?;
+
+        match record {
+            TraceRecord::Start { config } => {
+                let controller =
+                    Controller::from_config(backend.clone(), &config).with_clock(clock.clone());
+                core = Some(WorkerCore::new(Arc::new(controller)));
+            }
+            TraceRecord::Event { t_ms, event } => {
+                let Some(core) = core.as_mut() else {
+                    continue;
+                };
+                let now = at(t_ms);
+                println!("{:>8}ms  event     {:?}", t_ms, event);
+                core.on_event(&event.to_event(now), now);
+            }
+            TraceRecord::Devices {
+                default_id,
+                devices,
+                ..
+            } => {
+                backend.load(devices, default_id);
+            }
+            TraceRecord::Decision {
+                t_ms,
+                enabled,
+                locked_uid,
+                outcome,
+                immediate,
+            } => {
+                let Some(core) = core.as_mut() else {
+                    continue;
+                };
+                let now = at(t_ms);
+                let controller = core.controller();
+                let snapshot = controller.snapshot();
+                if snapshot.enabled != enabled {
+                    controller.set_enabled(enabled);
+                }
+                if snapshot.locked_uid != locked_uid {
+                    controller.set_locked_uid(locked_uid);
+                }
+
+                // An immediate decision left the worker's schedule alone.
+                let result = if immediate {
+                    controller.enforce()
+                } else {
+                    core.run(now)
+                };
+                let replayed = Outcome::new(&result, backend.as_ref());
+                let label = if immediate { "immediate" } else { "decision" };
+                println!("{:>8}ms  {:<10}{:?}", t_ms, label, replayed);
+                if replayed != outcome {
+                    mismatches += 1;
+                    println!("{:>8}    recorded  {:?}  (differs)", "", outcome);
+                }
+            }
+            TraceRecord::MissedNotification { t_ms, reinstalled } => {
+                // The decision before this record already replays the fix.
+                println!(
+                    "{:>8}ms  missed    notification{}",
+                    t_ms,
+                    if reinstalled {
+                        ", listeners reinstalled"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+    }
+
+    println!(
+        "replay finished, {} decision(s) differ from the trace",
+        mismatches
+    );
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_recorded_config_has_no_secrets() {
+        let config = Config {
+            lock_enabled: true,
+            hooks: [("locked".to_string(), "notify --token s3cret".to_string())].into(),
+            mqtt: serde_json::from_value(serde_json::json!({
+                "host": "broker.local",
+                "username": "me",
+                "password": "hunter2",
+            }))
+            .unwrap(),
+            ..Config::default()
+        };
+        let path =
+            std::env::temp_dir().join(format!("soundstoic-trace-{}.jsonl", std::process::id()));
+
+        drop(TraceRecorder::create(&path, &config).unwrap());
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        for secret in ["s3cret", "hunter2", "\"me\""] {
+            assert!(!text.contains(secret), "{} leaked into {}", secret, text);
+        }
+        let first = text.lines().next().unwrap();
+        let TraceRecord::Start { config: recorded } = serde_json::from_str(first).unwrap() else {
+            panic!("the trace does not start with the config");
+        };
+        assert!(recorded.lock_enabled);
+        assert_eq!(recorded.hooks["locked"], config::REDACTED);
+        assert_eq!(recorded.mqtt.unwrap().host, "broker.local");
+    }
+
+    #[test]
+    fn an_immediate_switch_at_startup_is_recorded_and_replayed() {
+        let config = Config {
+            lock_enabled: true,
+            locked_uid: Some("usb-mic".to_string()),
+            ..Config::default()
+        };
+        let backend = Arc::new(SimBackend::new());
+        let device = |id, uid: &str| SimDevice {
+            id,
+            uid: uid.to_string(),
+            name: uid.to_string(),
+            alive: true,
+            running: false,
+        };
+        backend.load(vec![device(1, "builtin"), device(2, "usb-mic")], Some(1));
+        let path = std::env::temp_dir().join(format!(
+            "soundstoic-trace-startup-{}.jsonl",
+            std::process::id()
+        ));
+        let recorder = Arc::new(TraceRecorder::create(&path, &config).unwrap());
+        let controller =
+            Controller::from_config(backend.clone(), &config).with_recorder(Some(recorder.clone()));
+
+        assert!(controller.enforce_now().unwrap().changed);
+        drop(controller);
+        drop(recorder);
+
+        let text = fs::read_to_string(&path).unwrap();
+        let mismatches = replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let decision: TraceRecord = serde_json::from_str(text.lines().last().unwrap()).unwrap();
+        let TraceRecord::Decision {
+            immediate, outcome, ..
+        } = decision
+        else {
+            panic!("the trace does not end with the decision");
+        };
+        assert!(immediate);
+        assert_eq!(outcome.default_uid.as_deref(), Some("usb-mic"));
+        assert_eq!(mismatches, 0);
+    }
+}
@@ -1,20 +1,27 @@
 use std::cell::OnceCell;
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use crossbeam_channel::Sender;
 use objc2::rc::Retained;
 use objc2::runtime::{AnyObject, ProtocolObject};
 use objc2::{define_class, msg_send, sel, ClassType, DefinedClass, MainThreadOnly};
 use objc2_app_kit::{
-    NSApplication, NSApplicationActivationPolicy, NSControlStateValueOff, NSControlStateValueOn,
-    NSImage, NSMenu, NSMenuItem, NSStatusBar, NSStatusItem, NSVariableStatusItemLength,
-    NSApplicationDelegate,
+    NSApplication, NSApplicationActivationPolicy, NSApplicationDelegate, NSControlStateValueOff,
+    NSControlStateValueOn, NSImage, NSMenu, NSMenuItem, NSStatusBar, NSStatusItem,
+    NSVariableStatusItemLength, NSWorkspace,
+};
+use objc2_foundation::{
+    ns_string, MainThreadMarker, NSNotification, NSObject, NSObjectProtocol, NSString,
 };
-use objc2_foundation::{ns_string, MainThreadMarker, NSObject, NSObjectProtocol, NSNotification, NSString};
 
-use crate::audio_manager;
+use crate::audio_backend;
+use crate::audio_manager::{self, DeviceInfo};
 use crate::autostart;
 use crate::config::ConfigStore;
-use crate::controller::{Controller, LockSnapshot};
+use crate::controller::{AudioEvent, Controller, LockSnapshot, SwitchOutcome};
+use crate::history::{self, History};
+use crate::support_bundle;
 use crate::ui_notifier::UiNotifier;
 
 fn load_status_image() -> Option<Retained<NSImage>> {
@@ -32,6 +39,9 @@ fn load_status_image() -> Option<Retained<NSImage>> {
     image
 }
 
+/// Entries shown under "Recent Events", newest first.
+const RECENT_EVENTS: usize = 10;
+
 #[derive(Default)]
 struct Ivars {
     status_item: OnceCell<Retained<NSStatusItem>>,
@@ -40,10 +50,16 @@ struct Ivars {
     devices_menu: OnceCell<Retained<NSMenu>>,
     toggle_lock_item: OnceCell<Retained<NSMenuItem>>,
     start_login_item: OnceCell<Retained<NSMenuItem>>,
+    aggressive_item: OnceCell<Retained<NSMenuItem>>,
     current_item: OnceCell<Retained<NSMenuItem>>,
     locked_item: OnceCell<Retained<NSMenuItem>>,
+    recent_menu: OnceCell<Retained<NSMenu>>,
+    pending_item: OnceCell<Retained<NSMenuItem>>,
+    pending_separator: OnceCell<Retained<NSMenuItem>>,
     controller: OnceCell<Arc<Controller>>,
     config: OnceCell<Arc<ConfigStore>>,
+    events: OnceCell<Sender<AudioEvent>>,
+    history: OnceCell<Arc<History>>,
 }
 
 define_class!(
@@ -79,6 +95,37 @@ define_class!(
             let menu = NSMenu::new(mtm);
             menu.setAutoenablesItems(false);
 
+            let pending = NSMenuItem::alloc(mtm);
+            let pending = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(
+                    pending,
+                    ns_string!("New Input: ..."),
+                    None,
+                    ns_string!(""),
+                )
+            };
+            let pending_menu = NSMenu::new(mtm);
+            pending_menu.setAutoenablesItems(false);
+            for (title, action) in [
+                (ns_string!("Allow"), sel!(allowNewInput:)),
+                (ns_string!("Lock to This"), sel!(lockToNewInput:)),
+                (ns_string!("Ignore Forever"), sel!(ignoreNewInput:)),
+            ] {
+                let item = NSMenuItem::alloc(mtm);
+                let item = unsafe {
+                    NSMenuItem::initWithTitle_action_keyEquivalent(item, title, Some(action), ns_string!(""))
+                };
+                unsafe { item.setTarget(Some(self)) };
+                pending_menu.addItem(&item);
+            }
+            pending.setSubmenu(Some(&pending_menu));
+            pending.setHidden(true);
+            menu.addItem(&pending);
+
+            let pending_separator = NSMenuItem::separatorItem(mtm);
+            pending_separator.setHidden(true);
+            menu.addItem(&pending_separator);
+
             let toggle = NSMenuItem::alloc(mtm);
             let toggle = unsafe {
                 NSMenuItem::initWithTitle_action_keyEquivalent(
@@ -130,8 +177,34 @@ define_class!(
             locked.setEnabled(false);
             menu.addItem(&locked);
 
+            let recent_item = NSMenuItem::alloc(mtm);
+            let recent_item = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(
+                    recent_item,
+                    ns_string!("Recent Events"),
+                    None,
+                    ns_string!(""),
+                )
+            };
+            let recent_menu = NSMenu::new(mtm);
+            recent_menu.setAutoenablesItems(false);
+            recent_item.setSubmenu(Some(&recent_menu));
+            menu.addItem(&recent_item);
+
             menu.addItem(&NSMenuItem::separatorItem(mtm));
 
+            let aggressive = NSMenuItem::alloc(mtm);
+            let aggressive = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(
+                    aggressive,
+                    ns_string!("Switch During Calls"),
+                    Some(sel!(toggleAggressiveSwitching:)),
+                    ns_string!(""),
+                )
+            };
+            unsafe { aggressive.setTarget(Some(self)) };
+            menu.addItem(&aggressive);
+
             let start_login = NSMenuItem::alloc(mtm);
             let start_login = unsafe {
                 NSMenuItem::initWithTitle_action_keyEquivalent(
@@ -144,6 +217,18 @@ define_class!(
             unsafe { start_login.setTarget(Some(self)) };
             menu.addItem(&start_login);
 
+            let support = NSMenuItem::alloc(mtm);
+            let support = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(
+                    support,
+                    ns_string!("Save Support Bundle…"),
+                    Some(sel!(saveSupportBundle:)),
+                    ns_string!(""),
+                )
+            };
+            unsafe { support.setTarget(Some(self)) };
+            menu.addItem(&support);
+
             menu.addItem(&NSMenuItem::separatorItem(mtm));
 
             let quit = NSMenuItem::alloc(mtm);
@@ -164,8 +249,12 @@ define_class!(
             self.ivars().devices_menu.set(devices_menu).ok();
             self.ivars().toggle_lock_item.set(toggle).ok();
             self.ivars().start_login_item.set(start_login).ok();
+            self.ivars().aggressive_item.set(aggressive).ok();
             self.ivars().current_item.set(current).ok();
             self.ivars().locked_item.set(locked).ok();
+            self.ivars().recent_menu.set(recent_menu).ok();
+            self.ivars().pending_item.set(pending).ok();
+            self.ivars().pending_separator.set(pending_separator).ok();
 
             self.refresh_menu_state_impl();
         }
@@ -183,7 +272,7 @@ define_class!(
             cfg.lock_enabled = !cfg.lock_enabled;
             self.config().update(|c| c.lock_enabled = cfg.lock_enabled);
             self.controller().set_enabled(cfg.lock_enabled);
-            let _ = self.controller().enforce();
+            self.enforce_now();
             self.refresh_menu_state_impl();
         }
 
@@ -196,14 +285,65 @@ define_class!(
                 .map(|s| s.to_string());
 
             if let Some(uid) = uid {
-                self.config().update(|c| c.locked_uid = Some(uid.clone()));
+                self.config().update(|c| {
+                    c.locked_uid = Some(uid.clone());
+                    if !c.known_uids.contains(&uid) {
+                        c.known_uids.push(uid.clone());
+                    }
+                });
+                self.controller().approve_device(&uid);
                 self.controller().set_locked_uid(Some(uid));
-                let _ = self.controller().enforce();
+                self.enforce_now();
             }
 
             self.refresh_menu_state_impl();
         }
 
+        #[unsafe(method(allowNewInput:))]
+        fn allow_new_input(&self, _sender: Option<&NSMenuItem>) {
+            let Some(pending) = self.lock_snapshot().pending else { return; };
+            self.remember_device(&pending.uid);
+            if let Err(e) = self.controller().allow_device(&pending.uid) {
+                tracing::warn!(uid = %pending.uid, error = ?e, "could not switch to the allowed device");
+            }
+            self.enforce_now();
+            self.refresh_menu_state_impl();
+        }
+
+        #[unsafe(method(lockToNewInput:))]
+        fn lock_to_new_input(&self, _sender: Option<&NSMenuItem>) {
+            let Some(pending) = self.lock_snapshot().pending else { return; };
+            self.remember_device(&pending.uid);
+            self.config().update(|c| {
+                c.locked_uid = Some(pending.uid.clone());
+                c.lock_enabled = true;
+            });
+            self.controller().set_locked_uid(Some(pending.uid));
+            self.controller().set_enabled(true);
+            self.enforce_now();
+            self.refresh_menu_state_impl();
+        }
+
+        #[unsafe(method(ignoreNewInput:))]
+        fn ignore_new_input(&self, _sender: Option<&NSMenuItem>) {
+            let Some(pending) = self.lock_snapshot().pending else { return; };
+            self.config().update(|c| {
+                if !c.ignored_uids.contains(&pending.uid) {
+                    c.ignored_uids.push(pending.uid.clone());
+                }
+            });
+            self.controller().ignore_device(&pending.uid);
+            self.refresh_menu_state_impl();
+        }
+
+        #[unsafe(method(toggleAggressiveSwitching:))]
+        fn toggle_aggressive_switching(&self, _sender: Option<&NSMenuItem>) {
+            let cfg = self.config().update(|c| c.aggressive_switching = !c.aggressive_switching);
+            self.controller().set_aggressive(cfg.aggressive_switching);
+            self.enforce_now();
+            self.refresh_menu_state_impl();
+        }
+
         #[unsafe(method(toggleStartAtLogin:))]
         fn toggle_start_at_login(&self, _sender: Option<&NSMenuItem>) {
             let current = autostart::is_enabled();
@@ -212,15 +352,40 @@ define_class!(
             }
             self.refresh_menu_state_impl();
         }
+
+        #[unsafe(method(saveSupportBundle:))]
+        fn save_support_bundle(&self, _sender: Option<&NSMenuItem>) {
+            let dir = dirs::desktop_dir()
+                .or_else(dirs::home_dir)
+                .unwrap_or_else(|| PathBuf::from("."));
+            let path = dir.join(support_bundle::file_name(history::now_ms()));
+            match support_bundle::write(&path, self.config()) {
+                Ok(()) => {
+                    tracing::info!(path = %path.display(), "saved a support bundle");
+                    let path = NSString::from_str(&path.to_string_lossy());
+                    NSWorkspace::sharedWorkspace()
+                        .selectFile_inFileViewerRootedAtPath(Some(&path), ns_string!(""));
+                }
+                Err(e) => tracing::warn!(error = %e, "could not save a support bundle"),
+            }
+        }
     }
 );
 
 impl AppDelegate {
-    pub fn new(mtm: MainThreadMarker, controller: Arc<Controller>, config: Arc<ConfigStore>) -> Retained<Self> {
+    pub fn new(
+        mtm: MainThreadMarker,
+        controller: Arc<Controller>,
+        config: Arc<ConfigStore>,
+        history: Arc<History>,
+        events: Sender<AudioEvent>,
+    ) -> Retained<Self> {
         let this = Self::alloc(mtm).set_ivars(Ivars::default());
         let this: Retained<Self> = unsafe { msg_send![super(this), init] };
         this.ivars().controller.set(controller).ok();
         this.ivars().config.set(config).ok();
+        this.ivars().events.set(events).ok();
+        this.ivars().history.set(history).ok();
         this
     }
 
@@ -232,15 +397,37 @@ impl AppDelegate {
         self.ivars().config.get().expect("config")
     }
 
+    /// Applies a menu change right away, then lets the worker follow up on
+    /// any recheck the lock asks for (a fresh self-set, a stability window).
+    fn enforce_now(&self) {
+        if let Err(e) = self.controller().enforce_now() {
+            tracing::warn!(error = ?e, "enforcing the lock failed");
+        }
+        if let Some(events) = self.ivars().events.get() {
+            let _ = events.send(AudioEvent::SettingsChanged);
+        }
+    }
+
     fn lock_snapshot(&self) -> LockSnapshot {
         self.controller().snapshot()
     }
 
+    fn remember_device(&self, uid: &str) {
+        self.config().update(|c| {
+            c.ignored_uids.retain(|u| u != uid);
+            if !c.known_uids.iter().any(|u| u == uid) {
+                c.known_uids.push(uid.to_string());
+            }
+        });
+        self.controller().approve_device(uid);
+    }
+
     fn refresh_menu_state_impl(&self) {
         let snapshot = self.lock_snapshot();
         self.update_status_title(&snapshot);
         self.update_menu_items(&snapshot);
         self.rebuild_devices_menu(&snapshot);
+        self.rebuild_recent_menu();
     }
 
     fn update_status_title(&self, snapshot: &LockSnapshot) {
@@ -252,6 +439,8 @@ impl AppDelegate {
             #[allow(deprecated)]
             if snapshot.enabled && snapshot.locked_missing {
                 item.setTitle(Some(ns_string!("MicLock!")));
+            } else if snapshot.pending.is_some() {
+                item.setTitle(Some(ns_string!("MicLock?")));
             } else {
                 item.setTitle(Some(ns_string!("MicLock")));
             }
@@ -259,6 +448,22 @@ impl AppDelegate {
     }
 
     fn update_menu_items(&self, snapshot: &LockSnapshot) {
+        if let Some(pending_item) = self.ivars().pending_item.get() {
+            match snapshot.pending.as_ref() {
+                Some(pending) => {
+                    let title = format!("New Input: {}", pending.name);
+                    let title = NSString::from_str(&title);
+                    pending_item.setTitle(&title);
+                    pending_item.setHidden(false);
+                }
+                None => pending_item.setHidden(true),
+            }
+        }
+
+        if let Some(separator) = self.ivars().pending_separator.get() {
+            separator.setHidden(snapshot.pending.is_none());
+        }
+
         if let Some(toggle) = self.ivars().toggle_lock_item.get() {
             toggle.setState(if snapshot.enabled {
                 NSControlStateValueOn
@@ -267,6 +472,14 @@ impl AppDelegate {
             });
         }
 
+        if let Some(aggressive) = self.ivars().aggressive_item.get() {
+            aggressive.setState(if self.config().get().aggressive_switching {
+                NSControlStateValueOn
+            } else {
+                NSControlStateValueOff
+            });
+        }
+
         if let Some(start_login) = self.ivars().start_login_item.get() {
             let enabled = autostart::is_enabled();
             start_login.setState(if enabled {
@@ -291,22 +504,34 @@ impl AppDelegate {
 
         if let Some(locked_item) = self.ivars().locked_item.get() {
             let title = match snapshot.locked_uid.as_deref() {
-                Some(uid) => match audio_manager::device_name_for_uid(uid) {
-                    Ok(name) => {
-                        if snapshot.locked_missing {
-                            format!("Locked Input: {} (missing)", name)
+                Some(uid) => {
+                    let name =
+                        audio_manager::device_name_for_uid(uid).unwrap_or_else(|_| uid.to_string());
+                    if snapshot.locked_missing {
+                        format!("Locked Input: {} (missing)", name)
+                    } else if let Some(failover) = snapshot.failed_over_to.as_deref() {
+                        let fallback = audio_manager::device_name_for_uid(failover)
+                            .unwrap_or_else(|_| failover.to_string());
+                        format!("Locked Input: {} (silent, using {})", name, fallback)
+                    } else if snapshot.deferred {
+                        format!("Locked Input: {} (waiting, input in use)", name)
+                    } else if let Some(outcome) = snapshot
+                        .last_switch
+                        .as_ref()
+                        .filter(|o| snapshot.enabled && **o != SwitchOutcome::Applied)
+                    {
+                        if snapshot.switch_gave_up {
+                            format!("Locked Input: {} (gave up, switch {})", name, outcome)
                         } else {
-                            format!("Locked Input: {}", name)
+                            format!("Locked Input: {} (retrying, switch {})", name, outcome)
                         }
+                    } else if let Some(remaining) = snapshot.stabilizing {
+                        let secs = remaining.as_millis().div_ceil(1000);
+                        format!("Locked Input: {} (switching in {}s)", name, secs)
+                    } else {
+                        format!("Locked Input: {}", name)
                     }
-                    Err(_) => {
-                        if snapshot.locked_missing {
-                            format!("Locked Input: {} (missing)", uid)
-                        } else {
-                            format!("Locked Input: {}", uid)
-                        }
-                    }
-                },
+                }
                 None => "Locked Input: (not set)".to_string(),
             };
             let title = NSString::from_str(&title);
@@ -315,10 +540,12 @@ impl AppDelegate {
     }
 
     fn rebuild_devices_menu(&self, snapshot: &LockSnapshot) {
-        let Some(menu) = self.ivars().devices_menu.get() else { return; };
+        let Some(menu) = self.ivars().devices_menu.get() else {
+            return;
+        };
         menu.removeAllItems();
 
-        let devices = match audio_manager::list_input_devices() {
+        let devices = match self.controller().backend().list_input_devices() {
             Ok(list) => list,
             Err(_) => Vec::new(),
         };
@@ -340,7 +567,7 @@ impl AppDelegate {
         }
 
         let mtm = self.mtm();
-        for device in devices {
+        for device in &devices {
             let title = NSString::from_str(&device.name);
             let item = NSMenuItem::alloc(mtm);
             let item = unsafe {
@@ -366,18 +593,94 @@ impl AppDelegate {
 
             menu.addItem(&item);
         }
+
+        self.add_processes_section(menu, &devices);
     }
 
+    fn rebuild_recent_menu(&self) {
+        let Some(menu) = self.ivars().recent_menu.get() else {
+            return;
+        };
+        menu.removeAllItems();
+
+        let entries = self
+            .ivars()
+            .history
+            .get()
+            .map(|h| h.recent(RECENT_EVENTS))
+            .unwrap_or_default();
+        let titles: Vec<String> = if entries.is_empty() {
+            vec!["No changes yet".to_string()]
+        } else {
+            entries
+                .iter()
+                .map(|entry| {
+                    let time = history::local_time(entry.at_ms);
+                    format!("{}  {}", time.get(5..16).unwrap_or(&time), entry)
+                })
+                .collect()
+        };
+
+        let mtm = self.mtm();
+        for title in titles {
+            let title = NSString::from_str(&title);
+            let item = NSMenuItem::alloc(mtm);
+            let item = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(item, &title, None, ns_string!(""))
+            };
+            item.setEnabled(false);
+            menu.addItem(&item);
+        }
+    }
+
+    fn add_processes_section(&self, menu: &NSMenu, devices: &[DeviceInfo]) {
+        let processes = self
+            .controller()
+            .backend()
+            .list_processes()
+            .unwrap_or_default();
+        let lines = audio_backend::in_use_by(&processes, devices);
+        if lines.is_empty() {
+            return;
+        }
+
+        let mtm = self.mtm();
+        menu.addItem(&NSMenuItem::separatorItem(mtm));
+
+        let header = NSMenuItem::alloc(mtm);
+        let header = unsafe {
+            NSMenuItem::initWithTitle_action_keyEquivalent(
+                header,
+                ns_string!("In use by"),
+                None,
+                ns_string!(""),
+            )
+        };
+        header.setEnabled(false);
+        menu.addItem(&header);
+
+        for title in lines {
+            let title = NSString::from_str(&title);
+            let item = NSMenuItem::alloc(mtm);
+            let item = unsafe {
+                NSMenuItem::initWithTitle_action_keyEquivalent(item, &title, None, ns_string!(""))
+            };
+            item.setEnabled(false);
+            menu.addItem(&item);
+        }
+    }
 }
 
 pub fn init_app(
     controller: Arc<Controller>,
     config: Arc<ConfigStore>,
+    history: Arc<History>,
+    events: Sender<AudioEvent>,
 ) -> (Retained<NSApplication>, UiNotifier) {
     let mtm = MainThreadMarker::new().expect("main thread");
     let app = NSApplication::sharedApplication(mtm);
 
-    let delegate = AppDelegate::new(mtm, controller, config);
+    let delegate = AppDelegate::new(mtm, controller, config, history, events);
     app.setDelegate(Some(ProtocolObject::from_ref(&*delegate)));
 
     let delegate_ptr = &*delegate as *const AppDelegate as *const AnyObject;
@@ -1,7 +1,7 @@
 use std::ptr;
 
-use objc2::{msg_send, sel};
 use objc2::runtime::AnyObject;
+use objc2::{msg_send, sel};
 
 #[derive(Clone, Copy)]
 pub struct UiNotifier {